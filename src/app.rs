@@ -1,18 +1,26 @@
 mod chat;
 mod input;
+mod picker;
+mod pull;
+mod recall;
 mod reconsile;
 mod settings;
 
 use crate::config::Config;
 use chat::Chat;
-use crossterm::event::{self, KeyEvent};
+use crossterm::event::{self, KeyEvent, KeyModifiers};
 use input::Input;
+use picker::{ModelPicker, Pick};
+use pull::Pull;
 use ratatui::widgets::Widget;
+use recall::{EmbeddingCache, Recall};
 use settings::Settings;
 use std::collections::VecDeque;
 use std::sync::mpsc;
 
-pub use chat::handle_streaming_request;
+pub use chat::Order;
+pub use chat::RetryPolicy;
+pub use chat::Scheduler;
 use ratatui::prelude::*;
 
 pub struct App {
@@ -21,6 +29,10 @@ pub struct App {
     pub input: Input,
     pub buffer: VecDeque<String>,
     pub view_ctx: ViewCtx,
+    pub picker: Option<ModelPicker>,
+    pub pulls: Vec<Pull>,
+    pub recall: Option<Recall>,
+    pub embeddings: EmbeddingCache,
     pub config: Config,
     pub send: mpsc::Sender<Signal>,
 
@@ -34,11 +46,17 @@ pub enum Signal {
 pub enum ViewCtx {
     Input,
     Complete,
+    Picker,
+    Recall,
 }
 
 impl App {
     pub fn new(coms: mpsc::Sender<Signal>) -> Self {
-        let config = Config::default();
+        let mut errors = Vec::new();
+        let config = Config::load().unwrap_or_else(|e| {
+            errors.push(e);
+            Config::default()
+        });
         let settings = Settings::new(config.clone().into());
         let input = Input::new();
 
@@ -47,11 +65,15 @@ impl App {
             chats: Vec::new(),
             input,
             view_ctx: ViewCtx::Input,
+            picker: None,
+            pulls: Vec::new(),
+            recall: None,
+            embeddings: EmbeddingCache::new(),
             config,
             buffer: VecDeque::new(),
             send: coms,
 
-            errors: Vec::new(),
+            errors,
         }
     }
 
@@ -63,13 +85,68 @@ impl App {
                     self.input.input.clear();
                     self.buffer.push_back(text);
                 }
+                event::KeyCode::Esc => self.stop(None),
+                event::KeyCode::Char('t') if key.modifiers == KeyModifiers::CONTROL => {
+                    self.toggle_thinking();
+                }
                 _ => {
                     self.input.on_key(key);
                 }
             },
             ViewCtx::Complete => {}
+            ViewCtx::Picker => {
+                let pick = match &mut self.picker {
+                    Some(picker) => picker.on_key(key),
+                    None => Pick::Close,
+                };
+                match pick {
+                    Pick::Stay => return,
+                    Pick::Close => {}
+                    Pick::Create(specs) => self
+                        .buffer
+                        .extend(specs.iter().map(|spec| format!("/create {}", spec))),
+                }
+                self.picker = None;
+                self.view_ctx = ViewCtx::Input;
+            }
+            ViewCtx::Recall => {
+                if self.recall.as_mut().is_none_or(|recall| recall.on_key(key)) {
+                    self.recall = None;
+                    self.view_ctx = ViewCtx::Input;
+                }
+            }
         }
     }
+
+    /// Opens the model picker for `endpoint`, or for the configured server.
+    pub fn open_picker(&mut self, endpoint: Option<String>) {
+        self.picker = Some(ModelPicker::new(endpoint));
+        self.view_ctx = ViewCtx::Picker;
+    }
+
+    /// Searches every chat for messages close in meaning to `query`.
+    pub fn open_recall(&mut self, query: String) {
+        self.recall = Some(Recall::new(query, &self.chats));
+        self.view_ctx = ViewCtx::Recall;
+    }
+}
+
+impl App {
+    /// Aborts the in-flight generation of the chat called `name`, or of every chat.
+    pub fn stop(&mut self, name: Option<&str>) {
+        self.chats
+            .iter_mut()
+            .filter(|chat| name.is_none_or(|name| chat.name == name))
+            .for_each(|chat| chat.stopping = chat.locked);
+    }
+
+    /// Unfolds the reasoning of every chat, or folds it back, so they stay comparable.
+    pub fn toggle_thinking(&mut self) {
+        let show = !self.chats.iter().any(|chat| chat.show_thinking);
+        self.chats
+            .iter_mut()
+            .for_each(|chat| chat.show_thinking = show);
+    }
 }
 
 pub struct State {
@@ -110,12 +187,25 @@ impl StatefulWidget for &App {
 
         let input_area = vertical_chunks[1];
 
-        let settings_area = horizontal_chunks[0];
+        let sidebar_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(0),
+                Constraint::Length(3 * self.pulls.len() as u16),
+            ])
+            .split(horizontal_chunks[0]);
+
+        let settings_area = sidebar_chunks[0];
+
+        let pull_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(self.pulls.iter().map(|_| Constraint::Length(3)))
+            .split(sidebar_chunks[1]);
 
         let chat_area = horizontal_chunks[1];
 
         match self.view_ctx {
-            ViewCtx::Input => {
+            ViewCtx::Input | ViewCtx::Picker | ViewCtx::Recall => {
                 self.settings
                     .render(settings_area, buf, &mut State::default());
                 self.input.render(input_area, buf, state);
@@ -126,6 +216,11 @@ impl StatefulWidget for &App {
             }
         }
 
+        self.pulls
+            .iter()
+            .zip(pull_chunks.iter())
+            .for_each(|(pull, area)| pull.render(*area, buf));
+
         let constaints = self
             .chats
             .iter()
@@ -142,5 +237,16 @@ impl StatefulWidget for &App {
             .for_each(|(chat, area)| {
                 chat.render(*area, buf);
             });
+
+        let overlay = chat_area.inner(&Margin {
+            horizontal: chat_area.width / 10,
+            vertical: chat_area.height / 6,
+        });
+        if let Some(picker) = &self.picker {
+            picker.render(overlay, buf);
+        }
+        if let Some(recall) = &self.recall {
+            recall.render(overlay, buf);
+        }
     }
 }
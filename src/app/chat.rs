@@ -1,42 +1,210 @@
+use base64::Engine;
 use ratatui::prelude::*;
 use ratatui::widgets::*;
-use std::any::Any;
-use std::sync::mpsc;
+use std::cell::Cell;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
+use crate::config::Config;
 use crate::logging::footstones::*;
 
-pub use self::backend::handle_streaming_request;
+pub use self::backend::http_client;
+pub use self::backend::ollama::embed;
+pub use self::backend::ollama::list_models;
+pub use self::backend::ollama::pull;
+pub use self::backend::ollama::ModelInfo;
+pub use self::backend::ollama::PullProgress;
+pub use self::backend::ollama_url;
+pub use self::backend::parse_option;
+pub use self::backend::ChatBackend;
 pub use self::backend::ChatRequest;
-pub use self::backend::ChatResponse;
+pub use self::backend::Dispatch;
+pub use self::backend::Job;
+pub use self::backend::Options;
+pub use self::backend::Order;
+pub use self::backend::RequestId;
+pub use self::backend::Responder;
+pub use self::backend::RetryPolicy;
+pub use self::backend::Scheduler;
+pub use self::backend::Stats;
+pub use self::backend::ToolCall;
+pub use self::backend::Update;
+pub use self::context::Strategy;
 
 mod backend;
+mod context;
+mod schema;
+mod thinking;
+mod tokens;
+mod tools;
+
+pub type ChatId = usize;
+
+static NEXT_CHAT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Rounds of tool calls a single user message may cause before the chat gives up on it.
+const MAX_TOOL_ROUNDS: usize = 8;
 
 pub struct Chat {
+    /// Unique for the lifetime of the process, unlike `name`.
+    pub id: ChatId,
     pub name: String,
+    pub model: String,
     pub messages: Vec<Message>,
+    /// Sent along with every request, set with `/set`.
+    pub options: Options,
+    /// Whether the model is offered the local tools, toggled with `/tools`.
+    pub tools: bool,
+    /// What replies have to look like, set with `/format`.
+    pub format: Option<Format>,
+    /// How much of the conversation is sent, set with `/context`.
+    pub context: Strategy,
+    /// Stands in for the messages [`Strategy::Summarize`] left out.
+    pub summary: Option<context::Summary>,
+    /// Whether the request for the current turn waits on a fresh summary.
+    pub summarizing: bool,
+    /// Whether the tool calls of the last reply are being run.
+    pub running_tools: bool,
+    /// Tokens spent on this chat so far, summaries aside.
+    pub usage: tokens::Usage,
+    /// Counts tokens where the server doesn't, and before anything is sent.
+    tokenizer: tokens::Tokenizer,
+    /// Estimated prompt tokens of the request in flight, for when the server doesn't count them.
+    sent_tokens: u64,
+    /// Estimated tokens of what is being typed, which the next request will carry.
+    draft_tokens: u64,
+    /// Whether reasoning is shown in full rather than folded into one line, toggled with ctrl+t.
+    pub show_thinking: bool,
 
     pub locked: bool,
     pub triggered: bool,
+    /// Set to abort the in-flight request on the next reconsile.
+    pub stopping: bool,
+    /// Set along with `stopping` when the turn was wiped, so there is nothing to mark interrupted.
+    discarding: bool,
+    /// Id of the request for the current turn; updates carrying any other id are stale.
+    pub turn: RequestId,
+    /// The failure that ended the last request, shown until the next one is sent.
+    pub error: Option<String>,
+    /// `(attempt, retries)` while the runtime is trying the current request again.
+    pub retrying: Option<(u32, u32)>,
+    /// Place in line while the request waits for its endpoint to free up.
+    pub queued: Option<usize>,
+    /// When the request for the current turn left the queue and was sent.
+    pub dispatched_at: Option<Instant>,
+    /// Picks `<think>` tags out of the bot message being streamed.
+    tags: thinking::ThinkTags,
 
-    pub channel: (mpsc::Sender<ChatResponse>, mpsc::Receiver<ChatResponse>),
+    pub backend: Arc<dyn ChatBackend>,
+    pub channel: (mpsc::Sender<Update>, mpsc::Receiver<Update>),
+    /// Where the summary for the current turn streams in, kept apart from the replies.
+    summaries: (mpsc::Sender<Update>, mpsc::Receiver<Update>),
+    /// The summary so far, while it streams in.
+    summary_draft: String,
+    ran: (mpsc::Sender<tools::Ran>, mpsc::Receiver<tools::Ran>),
 }
 
 impl Chat {
-    pub fn new(name: &str) -> Self {
+    pub fn new(name: &str, model: &str, backend: Arc<dyn ChatBackend>) -> Self {
         Self {
+            id: NEXT_CHAT_ID.fetch_add(1, Ordering::Relaxed),
             name: name.to_string(),
+            model: model.to_string(),
             messages: Vec::new(),
+            options: Options::new(),
+            tools: false,
+            format: None,
+            context: Strategy::Full,
+            summary: None,
+            summarizing: false,
+            running_tools: false,
+            usage: tokens::Usage::default(),
+            tokenizer: tokens::Tokenizer::for_model(model),
+            sent_tokens: 0,
+            draft_tokens: 0,
+            show_thinking: false,
             locked: false,
             triggered: false,
+            stopping: false,
+            discarding: false,
+            turn: 0,
+            error: None,
+            retrying: None,
+            queued: None,
+            dispatched_at: None,
+            tags: thinking::ThinkTags::default(),
+            backend,
             channel: mpsc::channel(),
+            summaries: mpsc::channel(),
+            summary_draft: String::new(),
+            ran: mpsc::channel(),
+        }
+    }
+
+    /// Replaces the system prompt, which always leads the conversation; `None` removes it.
+    pub fn set_system(&mut self, prompt: Option<&str>) {
+        self.messages.retain(|msg| msg.author != Author::System);
+        if let Some(prompt) = prompt {
+            self.messages
+                .insert(0, Message::new(Author::System, prompt));
         }
     }
+
+    /// Wipes the conversation, keeping only the system prompt. Whatever is still streaming for it
+    /// is aborted, and anything it already sent is ignored since it belongs to an older turn.
+    pub fn clear(&mut self) {
+        self.messages.retain(|msg| msg.author == Author::System);
+        self.summary = None;
+        self.summarizing = false;
+        self.running_tools = false;
+        self.triggered = false;
+        self.stopping = self.locked;
+        self.discarding = self.locked;
+        self.error = None;
+        self.retrying = None;
+        self.queued = None;
+        self.turn += 1;
+    }
+
+    /// Takes note of the input being typed, so the estimates cover the message it will become.
+    pub fn set_draft(&mut self, input: &str, attachments: &[Attachment]) {
+        self.draft_tokens = match input.trim() {
+            "" => 0,
+            command if command.starts_with('/') => 0,
+            message => {
+                self.tokenizer
+                    .count_message(&Message::new(Author::User, message))
+                    + attachments.len() as u64 * tokens::IMAGE_TOKENS
+            }
+        };
+    }
+
+    /// Creates a chat named after `spec`, see [`backend::resolve`] for the accepted forms.
+    pub fn from_spec(spec: &str, config: &Config) -> Result<Self, String> {
+        let (model, backend) = backend::resolve(spec, config)?;
+        Ok(Self::new(spec, &model, backend))
+    }
 }
 
 pub struct Message {
     pub author: Author,
     pub content: String,
-    metadata: Metadata,
+    /// What the model reasoned before answering, kept out of `content` and of later requests.
+    pub thinking: String,
+    /// The generation was stopped before the model finished this message.
+    pub interrupted: bool,
+    /// Images sent along with a user message.
+    pub attachments: Vec<Attachment>,
+    /// Tools a bot message asked to have run.
+    pub tool_calls: Vec<ToolCall>,
+    /// For tool messages, the call this is the result of.
+    pub tool_call_id: Option<String>,
+    pub metadata: Metadata,
+    /// Token count as part of a prompt, worked out when first needed. Reset whenever a streamed
+    /// reply grows, see [`tokens::Tokenizer::count_message`].
+    tokens: Cell<Option<u64>>,
 }
 
 impl Message {
@@ -44,35 +212,262 @@ impl Message {
         Self {
             author,
             content: content.to_string(),
-            metadata: Metadata(None),
+            thinking: String::new(),
+            interrupted: false,
+            attachments: Vec::new(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+            metadata: Metadata::default(),
+            tokens: Cell::new(None),
+        }
+    }
+}
+
+/// An image read from disk, queued with `/attach` until the next message is sent.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    /// File name, for display.
+    pub name: String,
+    /// Base64-encoded file contents.
+    pub data: String,
+}
+
+impl Attachment {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let name = Path::new(path).file_name().map_or_else(
+            || path.to_string(),
+            |name| name.to_string_lossy().to_string(),
+        );
+        Ok(Self {
+            name,
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+        })
+    }
+}
+
+/// Structured output: the model is asked for JSON, and each reply is checked once complete.
+#[derive(Debug, Clone)]
+pub enum Format {
+    Json,
+    Schema {
+        /// File the schema was loaded from, for display.
+        name: String,
+        schema: serde_json::Value,
+    },
+}
+
+impl Format {
+    /// `json` for any JSON value, anything else is taken as the path of a schema file.
+    pub fn load(arg: &str) -> Result<Self, String> {
+        if arg == "json" {
+            return Ok(Format::Json);
+        }
+
+        let content =
+            std::fs::read_to_string(arg).map_err(|e| format!("Failed to read {}: {}", arg, e))?;
+        let schema = serde_json::from_str(&content)
+            .map_err(|e| format!("{} is not a JSON schema: {}", arg, e))?;
+        let name = Path::new(arg).file_name().map_or_else(
+            || arg.to_string(),
+            |name| name.to_string_lossy().to_string(),
+        );
+        Ok(Format::Schema { name, schema })
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Format::Json => "json",
+            Format::Schema { name, .. } => name,
+        }
+    }
+
+    /// What goes into the request's `format` field.
+    fn wire(&self) -> serde_json::Value {
+        match self {
+            Format::Json => serde_json::Value::from("json"),
+            Format::Schema { schema, .. } => schema.clone(),
+        }
+    }
+
+    fn check(&self, content: &str) -> Result<(), String> {
+        let value = serde_json::from_str(content).map_err(|e| format!("$: not JSON: {}", e))?;
+        match self {
+            Format::Json => Ok(()),
+            Format::Schema { schema, .. } => schema::validate(schema, &value),
         }
     }
 }
 
 #[derive(Clone, PartialEq)]
 pub enum Author {
+    System,
     User,
     Bot,
+    Tool,
 }
 
-struct Metadata(Option<Box<dyn Any>>);
+/// How a bot message came to be.
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    /// Timings and token counts reported by the server on the final chunk.
+    pub stats: Stats,
+    pub done_reason: Option<String>,
+    /// From the request leaving the queue for its endpoint until its first chunk arrived, by our
+    /// own clock. Time spent waiting in line doesn't count.
+    pub time_to_first_token: Option<Duration>,
+    /// From the request leaving the queue for its endpoint until its final chunk arrived.
+    pub total_time: Option<Duration>,
+    /// Outcome of checking the reply against the chat's format, if it has one.
+    pub validation: Option<Result<(), String>>,
+    /// Tokens spent reasoning, and whether the server counted them or we guessed.
+    pub thinking_tokens: Option<(u64, bool)>,
+}
+
+impl Metadata {
+    /// Generation speed, preferring the server's own eval timing over our clock, which also
+    /// counts network time.
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        let eval_count = self.stats.eval_count? as f64;
+        let seconds = match self.stats.eval_duration {
+            Some(nanos) => Duration::from_nanos(nanos).as_secs_f64(),
+            None => (self.total_time? - self.time_to_first_token?).as_secs_f64(),
+        };
+        (seconds > 0.0).then(|| eval_count / seconds)
+    }
+
+    /// One line summary shown under the message, empty until something is known.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(eval_count) = self.stats.eval_count {
+            parts.push(format!("{} tok", eval_count));
+        }
+        if let Some((thinking, exact)) = self.thinking_tokens {
+            let approx = if exact { "" } else { "~" };
+            parts.push(format!("{}{} thinking", approx, thinking));
+        }
+        if let Some(rate) = self.tokens_per_second() {
+            parts.push(format!("{:.1} tok/s", rate));
+        }
+        if let Some(ttft) = self.time_to_first_token {
+            parts.push(format!("first token {:.2}s", ttft.as_secs_f64()));
+        }
+        if let Some(total) = self.total_time {
+            parts.push(format!("total {:.2}s", total.as_secs_f64()));
+        }
+        if let Some(reason) = &self.done_reason {
+            parts.push(reason.clone());
+        }
+        if let Some(Ok(())) = self.validation {
+            parts.push("valid".to_string());
+        }
+        parts.join(" · ")
+    }
+}
 
 impl Widget for &Chat {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let itemsspans = self.messages.iter().map(|msg| {
+        let itemsspans = self.messages.iter().flat_map(|msg| {
             let author = match msg.author {
+                Author::System => Span::styled("System", Style::default().fg(Color::Magenta)),
                 Author::User => Span::styled("User", Style::default().fg(Color::Yellow)),
                 Author::Bot => Span::styled("Bot", Style::default().fg(Color::Green)),
+                Author::Tool => Span::styled("Tool", Style::default().fg(Color::Blue)),
+            };
+            let content = match msg.author {
+                Author::System => Span::raw(msg.content.as_str()).italic(),
+                // Results can be whole files, the first line is enough to tell what happened.
+                Author::Tool => {
+                    let mut lines = msg.content.lines();
+                    let first = lines.next().unwrap_or_default();
+                    match lines.count() {
+                        0 => Span::raw(first).dark_gray(),
+                        more => Span::raw(format!("{} (+{} lines)", first, more)).dark_gray(),
+                    }
+                }
+                _ => Span::raw(msg.content.as_str()),
             };
-            Line::default().spans([author, Span::raw(": "), Span::raw(&msg.content)])
+            // Folded, reasoning takes a single line however long it went on.
+            let thinking = (!msg.thinking.is_empty()).then(|| match self.show_thinking {
+                true => Line::default().spans([Span::raw(msg.thinking.trim()).dim().italic()]),
+                false => Line::default().spans([Span::raw(format!(
+                    "  ▸ thinking, {} lines (ctrl+t to show)",
+                    msg.thinking.trim().lines().count()
+                ))
+                .dim()
+                .italic()]),
+            });
+            let mut line = Line::default().spans([author, Span::raw(": "), content]);
+            for attachment in &msg.attachments {
+                line.push_span(Span::raw(format!(" [{}]", attachment.name)).cyan());
+            }
+            if msg.interrupted {
+                line.push_span(Span::raw(" [interrupted]").dark_gray().italic());
+            }
+
+            let calls = msg.tool_calls.iter().map(|call| {
+                Line::default().spans([
+                    Span::raw("  → ").magenta(),
+                    Span::raw(call.function.name.as_str()).magenta().bold(),
+                    Span::raw(format!(" {}", call.function.arguments)).magenta(),
+                ])
+            });
+            let summary = msg.metadata.summary();
+            let summary = (!summary.is_empty())
+                .then(|| Line::default().spans([Span::raw(format!("  {}", summary)).dark_gray()]));
+            let invalid = match &msg.metadata.validation {
+                Some(Err(error)) => Some(Line::default().spans([
+                    Span::raw("  Invalid").red().bold(),
+                    Span::raw(": "),
+                    error.as_str().red(),
+                ])),
+                _ => None,
+            };
+            thinking
+                .into_iter()
+                .chain(std::iter::once(line))
+                .chain(calls)
+                .chain(summary)
+                .chain(invalid)
+        });
+        let error = self.error.iter().map(|error| {
+            Line::default().spans([
+                Span::raw("Error").red().bold(),
+                Span::raw(": "),
+                error.as_str().red(),
+            ])
         });
 
+        // Counting is the costly part of drawing a chat, it is done once per frame.
+        let next_tokens = self.next_tokens();
+        let label = self.label(next_tokens);
+        let block = match (&self.error, self.locked) {
+            (_, true) if self.summarizing => {
+                Block::bordered().title(format!("{} summarizing", label).yellow())
+            }
+            (_, true) if self.running_tools => {
+                Block::bordered().title(format!("{} running tools", label).yellow())
+            }
+            (_, true) => match (self.queued, self.retrying) {
+                (Some(position), _) => {
+                    Block::bordered().title(format!("{} queued #{}", label, position).blue())
+                }
+                (None, Some((attempt, retries))) => Block::bordered()
+                    .title(format!("{} retrying ({}/{})", label, attempt, retries).yellow()),
+                (None, None) => Block::bordered().title(format!("{} (Locked)", label).red()),
+            },
+            (Some(_), false) => Block::bordered()
+                .title(format!("{} (Error)", label).red())
+                .border_style(Style::default().fg(Color::Red)),
+            (None, false) => Block::bordered().title(label.green()),
+        };
+
+        let block = block
+            .title_bottom(Line::from(self.token_line(next_tokens).dark_gray()).right_aligned());
+
         Widget::render(
-            Paragraph::new(itemsspans.collect::<Vec<_>>())
-                .block(Block::bordered().title(match self.locked {
-                    true => format!("{} (Locked)", self.name).red(),
-                    false => self.name.to_string().green(),
-                }))
+            Paragraph::new(itemsspans.chain(error).collect::<Vec<_>>())
+                .block(block)
                 .wrap(Wrap { trim: true }),
             area,
             buf,
@@ -81,35 +476,311 @@ impl Widget for &Chat {
 }
 
 impl Chat {
-    pub fn reconsile(
-        &mut self,
-        request_handle: mpsc::Sender<(mpsc::Sender<ChatResponse>, ChatRequest)>,
-    ) {
+    /// The name, followed by whichever options are set, e.g. `llama3 [temperature=0.2 seed=7]`,
+    /// whether tools are on, the output format, the context strategy and how full `next_tokens`
+    /// leave the context.
+    fn label(&self, next_tokens: u64) -> String {
+        let mut label = self.name.clone();
+        if !self.options.is_empty() {
+            let options = self
+                .options
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join(" ");
+            label += &format!(" [{}]", options);
+        }
+        if self.tools {
+            label += " +tools";
+        }
+        if let Some(format) = &self.format {
+            label += &format!(" {{{}}}", format.name());
+        }
+        if let Some(strategy) = self.context.name() {
+            label += &format!(" <{}>", strategy);
+        }
+        label += " ";
+        label += &context::meter(next_tokens, self.context_size(), self.tokenizer.exact);
+        label
+    }
+
+    /// Estimated prompt tokens of the next request, counting the message being typed.
+    fn next_tokens(&self) -> u64 {
+        match self.locked {
+            true => self.context_tokens(),
+            false => self.context_tokens() + self.draft_tokens,
+        }
+    }
+
+    /// The next prompt's size and what the chat used up so far, e.g.
+    /// `next ~1.2k · used 5.4k (5k in, 420 out) · ~ estimated`. `~` marks estimates, and the
+    /// legend only shows along with one.
+    fn token_line(&self, next_tokens: u64) -> String {
+        let next = if self.tokenizer.exact { "" } else { "~" };
+        let used = if self.usage.estimated { "~" } else { "" };
+        let legend = match (next, used) {
+            ("", "") => "",
+            _ => " · ~ estimated",
+        };
+        format!(
+            "next {}{} · used {}{} ({} in, {} out){}",
+            next,
+            tokens::compact(next_tokens),
+            used,
+            tokens::compact(self.usage.total()),
+            tokens::compact(self.usage.prompt),
+            tokens::compact(self.usage.completion),
+            legend
+        )
+    }
+
+    /// Tokens the model can take in, as far as the chat knows: `num_ctx` if set, else the
+    /// window's budget.
+    fn context_size(&self) -> Option<u64> {
+        let num_ctx = self.options.get("num_ctx").and_then(|value| value.as_u64());
+        num_ctx.or(match self.context {
+            Strategy::Window { budget } => Some(budget),
+            _ => None,
+        })
+    }
+
+    /// Has the calls of the last bot message run, their results go to the model once they are in.
+    /// Gives up when it has been at it for too long already.
+    fn run_tools(&mut self, request_handle: &mpsc::Sender<Order>) {
+        let Some(last) = self.messages.last() else {
+            return;
+        };
+        if last.author != Author::Bot || last.tool_calls.is_empty() {
+            return;
+        }
+
+        let rounds = self
+            .messages
+            .iter()
+            .rev()
+            .take_while(|msg| msg.author != Author::User)
+            .filter(|msg| !msg.tool_calls.is_empty())
+            .count();
+        if rounds > MAX_TOOL_ROUNDS {
+            let reason = format!("gave up after {} rounds of tool calls", MAX_TOOL_ROUNDS);
+            self.fail_tool_calls(&reason);
+            self.error = Some(format!(
+                "Gave up after {} rounds of tool calls",
+                MAX_TOOL_ROUNDS
+            ));
+            return;
+        }
+
+        let job = tools::job(last.tool_calls.clone(), self.turn, self.ran.0.clone());
+        self.running_tools = true;
+        self.locked = true;
+        request_handle.send(Order::Spawn(job)).unwrap();
+    }
+
+    /// Answers every call of the last bot message with `reason`, since calls left without an
+    /// answer make the next request malformed.
+    fn fail_tool_calls(&mut self, reason: &str) {
+        let outputs = self.messages.last().map_or_else(Vec::new, |last| {
+            last.tool_calls
+                .iter()
+                .map(|call| (call.id.clone(), format!("error: {}", reason)))
+                .collect()
+        });
+        self.push_tool_results(outputs);
+    }
+
+    /// Answers the calls of the last bot message with `outputs`, as `(call id, output)` pairs.
+    fn push_tool_results(&mut self, outputs: Vec<(Option<String>, String)>) {
+        self.messages
+            .extend(outputs.into_iter().map(|(id, output)| {
+                let mut result = Message::new(Author::Tool, &output);
+                result.tool_call_id = id;
+                result
+            }));
+    }
+
+    pub fn reconsile(&mut self, request_handle: mpsc::Sender<Order>) {
+        if self.stopping {
+            self.stopping = false;
+            // A wiped turn has nothing left to mark interrupted.
+            let discarding = std::mem::take(&mut self.discarding);
+            if self.locked {
+                self.locked = false;
+                self.queued = None;
+                self.summarizing = false;
+                let running_tools = std::mem::take(&mut self.running_tools);
+                request_handle.send(Order::Stop(self.id)).unwrap();
+
+                match self.messages.last_mut() {
+                    _ if discarding => {}
+                    // The calls still need answers, or the next request is malformed.
+                    Some(_) if running_tools => self.fail_tool_calls("stopped by the user"),
+                    Some(last) if last.author == Author::Bot => last.interrupted = true,
+                    Some(_) => {
+                        let mut message = Message::new(Author::Bot, "");
+                        message.interrupted = true;
+                        self.messages.push(message);
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        if self.running_tools {
+            match self.ran.1.try_recv() {
+                Ok((turn, _)) if turn != self.turn => {}
+                Ok((_, outputs)) => {
+                    self.running_tools = false;
+                    self.push_tool_results(outputs);
+                    self.triggered = true;
+                }
+                Err(_) => {}
+            }
+        }
+
         if self.triggered {
             self.triggered = false;
             self.locked = true;
-            let request = self.construct_request();
+            self.error = None;
+            self.turn += 1;
 
-            info!("Sent request: {:?}", request);
+            match self.summarize() {
+                Some(dispatch) => {
+                    self.summarizing = true;
+                    self.summary_draft.clear();
+                    self.tags = thinking::ThinkTags::default();
+                    request_handle.send(Order::Generate(dispatch)).unwrap();
+                }
+                None => self.dispatch(&request_handle),
+            }
+        }
 
-            request_handle
-                .send((self.channel.0.clone(), request))
-                .unwrap();
+        while self.summarizing {
+            match self.summaries.1.try_recv() {
+                Ok(update) if update.id() != self.turn => {}
+                Ok(Update::Chunk(value)) => {
+                    self.retrying = None;
+                    self.summary_draft += &self.tags.feed(&value.message.content).1;
+                    if !value.done {
+                        continue;
+                    }
+                    self.summarizing = false;
+                    self.summary_draft += &self.tags.finish().1;
+                    let text = std::mem::take(&mut self.summary_draft).trim().to_string();
+                    if text.is_empty() {
+                        self.locked = false;
+                        self.error =
+                            Some("Failed to summarize: the summary came back empty".to_string());
+                        break;
+                    }
+                    let start = self.context.start(&self.messages, &self.tokenizer);
+                    self.summary = Some(context::Summary {
+                        covers: context::folded(&self.messages, start).count(),
+                        tokens: self.tokenizer.count(&text),
+                        text,
+                    });
+                    self.dispatch(&request_handle);
+                }
+                Ok(Update::Retrying {
+                    attempt, retries, ..
+                }) => {
+                    self.retrying = Some((attempt, retries));
+                }
+                Ok(Update::Queued { position, .. }) => {
+                    self.queued = Some(position);
+                }
+                Ok(Update::Dispatched { .. }) => {
+                    self.queued = None;
+                }
+                Ok(Update::Failed(_, error)) => {
+                    self.summarizing = false;
+                    self.locked = false;
+                    self.retrying = None;
+                    self.error = Some(format!("Failed to summarize: {}", error));
+                }
+                Err(_) => break,
+            }
         }
 
-        if self.locked {
+        while self.locked {
             match self.channel.1.try_recv() {
-                Ok(value) => {
+                Ok(update) if update.id() != self.turn => {
+                    debug!("Dropping stale update for {}: {:?}", self.name, update);
+                }
+                Ok(Update::Chunk(value)) => {
                     self.locked = !value.done;
-                    if self.messages.last().unwrap().author == Author::User {
-                        self.messages
-                            .push(Message::new(Author::Bot, &value.message.content));
-                    } else {
-                        self.messages.last_mut().unwrap().content += &value.message.content;
+                    self.retrying = None;
+                    let elapsed = value
+                        .received_at
+                        .zip(self.dispatched_at)
+                        .map(|(received_at, dispatched_at)| received_at - dispatched_at);
+
+                    if self.messages.last().unwrap().author != Author::Bot {
+                        let mut message = Message::new(Author::Bot, "");
+                        message.metadata.time_to_first_token = elapsed;
+                        self.messages.push(message);
+                        self.tags = thinking::ThinkTags::default();
+                    }
+                    let last = self.messages.last_mut().unwrap();
+                    let (thinking, content) = self.tags.feed(&value.message.content);
+                    last.thinking += &value.message.thinking;
+                    last.thinking += &thinking;
+                    last.content += &content;
+                    last.tool_calls.extend(value.message.tool_calls);
+                    last.tokens.set(None);
+
+                    if value.done {
+                        let (thinking, content) = self.tags.finish();
+                        last.thinking += &thinking;
+                        last.content += &content;
+                        last.tokens.set(None);
+                        self.usage.add(
+                            (value.stats.prompt_eval_count, value.stats.eval_count),
+                            (
+                                self.sent_tokens,
+                                self.tokenizer.count(&last.content)
+                                    + self.tokenizer.count(&last.thinking),
+                            ),
+                            self.tokenizer.exact,
+                        );
+                        if !last.thinking.is_empty() {
+                            last.metadata.thinking_tokens =
+                                Some(match value.stats.thinking_count {
+                                    Some(count) => (count, true),
+                                    None => (self.tokenizer.count(&last.thinking), false),
+                                });
+                        }
+                        last.metadata.stats = value.stats;
+                        last.metadata.done_reason = value.done_reason;
+                        last.metadata.total_time = elapsed;
+                        if let Some(format) = &self.format {
+                            if last.tool_calls.is_empty() {
+                                last.metadata.validation = Some(format.check(&last.content));
+                            }
+                        }
+                        self.run_tools(&request_handle);
                     }
                 }
+                Ok(Update::Retrying {
+                    attempt, retries, ..
+                }) => {
+                    self.retrying = Some((attempt, retries));
+                }
+                Ok(Update::Queued { position, .. }) => {
+                    self.queued = Some(position);
+                }
+                Ok(Update::Dispatched { at, .. }) => {
+                    // Latency is measured from here, time spent in line doesn't count.
+                    self.queued = None;
+                    self.dispatched_at = Some(at);
+                }
+                Ok(Update::Failed(_, error)) => {
+                    self.locked = false;
+                    self.retrying = None;
+                    self.error = Some(error.to_string());
+                }
                 Err(err) => match err {
-                    mpsc::TryRecvError::Empty => {}
+                    mpsc::TryRecvError::Empty => break,
                     mpsc::TryRecvError::Disconnected => {
                         self.locked = false;
                     }
@@ -118,20 +789,602 @@ impl Chat {
         }
     }
 
+    /// Sends the request for the current turn.
+    fn dispatch(&mut self, request_handle: &mpsc::Sender<Order>) {
+        self.dispatched_at = Some(Instant::now());
+        self.sent_tokens = self.context_tokens();
+        let request = self.construct_request();
+
+        info!("Sent request: {:?}", request);
+
+        request_handle
+            .send(Order::Generate(Dispatch {
+                chat: self.id,
+                backend: self.backend.clone(),
+                tx: Responder::new(self.channel.0.clone(), self.turn),
+                request,
+            }))
+            .unwrap();
+    }
+
+    /// A request bringing the summary up to date, if the strategy wants one and messages left the
+    /// context since it was written.
+    fn summarize(&self) -> Option<Dispatch> {
+        let Strategy::Summarize { summarizer, .. } = &self.context else {
+            return None;
+        };
+        let start = self.context.start(&self.messages, &self.tokenizer);
+        let covered = self.summary.as_ref().map_or(0, |summary| summary.covers);
+        let folded = context::folded(&self.messages, start);
+        if folded.clone().count() <= covered {
+            return None;
+        }
+
+        let (model, backend) = match summarizer {
+            Some((model, backend)) => (model.as_str(), backend.clone()),
+            None => (self.model.as_str(), self.backend.clone()),
+        };
+        Some(Dispatch {
+            chat: self.id,
+            backend,
+            tx: Responder::new(self.summaries.0.clone(), self.turn),
+            request: context::request(
+                model,
+                self.summary.as_ref(),
+                folded.skip(covered),
+                self.turn,
+            ),
+        })
+    }
+
+    /// The messages that go out under the chat's strategy, and the summary standing in for the
+    /// ones left out, if it has one.
+    fn in_context(&self) -> (Option<&context::Summary>, Vec<&Message>) {
+        let start = self.context.start(&self.messages, &self.tokenizer);
+        let summary = match self.context {
+            Strategy::Summarize { .. } => self.summary.as_ref(),
+            _ => None,
+        };
+        // A summary written for a smaller window may reach past `start`, nothing goes out twice.
+        let covered = summary.map_or(0, |summary| summary.covers);
+
+        let mut seen = 0;
+        let messages = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(index, msg)| {
+                if msg.author == Author::System {
+                    return true;
+                }
+                // Counted like the summary counts it, even when it doesn't go out.
+                seen += 1;
+                // Stopped before its first token, the reply is only a marker for the reader.
+                if msg.author == Author::Bot
+                    && msg.interrupted
+                    && msg.content.is_empty()
+                    && msg.tool_calls.is_empty()
+                {
+                    return false;
+                }
+                *index >= start && seen > covered
+            })
+            .map(|(_, msg)| msg)
+            .collect();
+        (summary, messages)
+    }
+
+    /// Tokens of the next request's messages, see [`tokens::Tokenizer`].
+    fn context_tokens(&self) -> u64 {
+        let (summary, messages) = self.in_context();
+        let summary = summary.map_or(0, |summary| summary.tokens);
+        summary
+            + messages
+                .into_iter()
+                .map(|msg| self.tokenizer.count_message(msg))
+                .sum::<u64>()
+    }
+
     fn construct_request(&self) -> ChatRequest {
-        ChatRequest {
-            model: self.name.clone(),
-            messages: self
-                .messages
+        let (summary, context) = self.in_context();
+        let mut messages = context
+            .into_iter()
+            .map(|msg| backend::Message {
+                role: match msg.author {
+                    Author::System => backend::Role::System,
+                    Author::Tool => backend::Role::Tool,
+                    Author::Bot => backend::Role::Assistant,
+                    Author::User => backend::Role::User,
+                },
+                content: msg.content.clone(),
+                // Reasoning was for the reader, the model starts afresh every turn.
+                thinking: String::new(),
+                images: msg
+                    .attachments
+                    .iter()
+                    .map(|attachment| attachment.data.clone())
+                    .collect(),
+                tool_calls: msg.tool_calls.clone(),
+                tool_call_id: msg.tool_call_id.clone(),
+            })
+            .collect::<Vec<_>>();
+        if let Some(summary) = summary {
+            let after_system = messages
                 .iter()
-                .map(|msg| backend::Message {
-                    role: match msg.author {
-                        Author::Bot => backend::Role::Assistant,
-                        Author::User => backend::Role::User,
-                    },
-                    content: msg.content.clone(),
-                })
-                .collect(),
+                .take_while(|msg| matches!(msg.role, backend::Role::System))
+                .count();
+            messages.insert(
+                after_system,
+                backend::Message {
+                    role: backend::Role::System,
+                    content: format!("Summary of the earlier conversation:\n{}", summary.text),
+                    ..Default::default()
+                },
+            );
+        }
+
+        ChatRequest {
+            id: self.turn,
+            model: self.model.clone(),
+            messages,
+            options: self.options.clone(),
+            tools: if self.tools {
+                tools::specs()
+            } else {
+                Vec::new()
+            },
+            format: self.format.as_ref().map(Format::wire),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, BoxFuture};
+
+    /// Never streams anything on its own; the tests drive the responders by hand.
+    #[derive(Debug)]
+    struct Silent;
+
+    impl ChatBackend for Silent {
+        fn stream<'a>(
+            &'a self,
+            _request: ChatRequest,
+            _tx: &'a Responder,
+        ) -> BoxFuture<'a, Result<(), BackendError>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn endpoint(&self) -> &str {
+            "silent"
+        }
+    }
+
+    fn chunk(content: &str, done: bool) -> backend::ChatResponse {
+        backend::ChatResponse::new("silent".to_string(), content.to_string(), done)
+    }
+
+    /// Sends `text` the way a broadcast message would and returns the responder of the request
+    /// it produced.
+    fn say(
+        chat: &mut Chat,
+        orders: &mpsc::Sender<Order>,
+        rx: &mpsc::Receiver<Order>,
+        text: &str,
+    ) -> Responder {
+        chat.messages.push(Message::new(Author::User, text));
+        chat.triggered = true;
+        chat.reconsile(orders.clone());
+        match rx.try_recv().unwrap() {
+            Order::Generate(dispatch) => dispatch.tx,
+            order => panic!("unexpected order: {:?}", order),
+        }
+    }
+
+    fn transcript(chat: &Chat) -> Vec<&str> {
+        chat.messages
+            .iter()
+            .map(|msg| msg.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_clear_drops_chunks_of_previous_turn() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        let old = say(&mut chat, &orders, &rx, "first");
+        old.chunk(chunk("Hel", false)).unwrap();
+        chat.reconsile(orders.clone());
+        assert_eq!(transcript(&chat), ["first", "Hel"]);
+
+        chat.clear();
+        old.chunk(chunk("lo", false)).unwrap();
+        chat.reconsile(orders.clone());
+        assert!(matches!(rx.try_recv().unwrap(), Order::Stop(id) if id == chat.id));
+        assert!(chat.messages.is_empty());
+        assert!(!chat.locked);
+
+        let new = say(&mut chat, &orders, &rx, "second");
+        old.chunk(chunk(" world", true)).unwrap();
+        new.chunk(chunk("Hi", false)).unwrap();
+        old.fail(BackendError::Disconnected);
+        chat.reconsile(orders.clone());
+        assert_eq!(transcript(&chat), ["second", "Hi"]);
+        assert!(chat.locked);
+        assert!(chat.error.is_none());
+
+        new.chunk(chunk("!", true)).unwrap();
+        chat.reconsile(orders.clone());
+        assert_eq!(transcript(&chat), ["second", "Hi!"]);
+        assert!(!chat.locked);
+    }
+
+    #[test]
+    fn test_clear_keeps_only_the_system_prompt() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+        chat.set_system(Some("Be brief."));
+
+        let old = say(&mut chat, &orders, &rx, "first");
+        chat.clear();
+        old.chunk(chunk("Hel", false)).unwrap();
+        chat.reconsile(orders.clone());
+
+        assert!(matches!(rx.try_recv().unwrap(), Order::Stop(_)));
+        assert_eq!(transcript(&chat), ["Be brief."]);
+        assert!(!chat.locked);
+    }
+
+    #[test]
+    fn test_stop_ignores_late_chunks() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        let old = say(&mut chat, &orders, &rx, "first");
+        old.chunk(chunk("Par", false)).unwrap();
+        chat.reconsile(orders.clone());
+
+        chat.stopping = true;
+        chat.reconsile(orders.clone());
+        assert!(matches!(rx.try_recv().unwrap(), Order::Stop(_)));
+        assert!(chat.messages.last().unwrap().interrupted);
+
+        // Already in the channel when the task got aborted.
+        old.chunk(chunk("tial", false)).unwrap();
+        let new = say(&mut chat, &orders, &rx, "second");
+        new.chunk(chunk("Fresh", true)).unwrap();
+        chat.reconsile(orders.clone());
+        assert_eq!(transcript(&chat), ["first", "Par", "second", "Fresh"]);
+    }
+
+    #[test]
+    fn test_stop_before_first_token_sends_no_empty_reply() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        say(&mut chat, &orders, &rx, "first");
+        chat.stopping = true;
+        chat.reconsile(orders.clone());
+        assert!(matches!(rx.try_recv().unwrap(), Order::Stop(_)));
+        assert!(chat.messages.last().unwrap().interrupted);
+
+        say(&mut chat, &orders, &rx, "second");
+        let request = chat.construct_request();
+        let contents = request
+            .messages
+            .iter()
+            .map(|msg| msg.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["first", "second"]);
+    }
+
+    #[test]
+    fn test_stopped_reply_in_the_summary_keeps_the_latest_turn() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        say(&mut chat, &orders, &rx, "one");
+        chat.stopping = true;
+        chat.reconsile(orders.clone());
+        assert!(matches!(rx.try_recv().unwrap(), Order::Stop(_)));
+        let two = say(&mut chat, &orders, &rx, "two");
+        two.chunk(chunk("ok", true)).unwrap();
+        chat.reconsile(orders.clone());
+        chat.messages.push(Message::new(Author::User, "three"));
+
+        chat.context = Strategy::Summarize {
+            keep: 1,
+            summarizer: None,
+        };
+        let start = chat.context.start(&chat.messages, &chat.tokenizer);
+        chat.summary = Some(context::Summary {
+            covers: context::folded(&chat.messages, start).count(),
+            text: "S".to_string(),
+            tokens: 1,
+        });
+        let request = chat.construct_request();
+        let contents = request
+            .messages
+            .iter()
+            .map(|msg| msg.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            ["Summary of the earlier conversation:\nS", "three"]
+        );
+    }
+
+    #[test]
+    fn test_system_prompt_leads_the_request() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        let first = say(&mut chat, &orders, &rx, "first");
+        first.chunk(chunk("Ahoy", true)).unwrap();
+        chat.reconsile(orders.clone());
+        chat.set_system(Some("Talk like a pirate."));
+        chat.set_system(Some("Talk like a sailor."));
+
+        let request = chat.construct_request();
+        assert!(matches!(request.messages[0].role, backend::Role::System));
+        assert_eq!(request.messages[0].content, "Talk like a sailor.");
+        assert_eq!(request.messages.len(), 3);
+
+        chat.clear();
+        assert_eq!(transcript(&chat), ["Talk like a sailor."]);
+        chat.set_system(None);
+        assert!(chat.messages.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_continue_the_turn() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+        chat.tools = true;
+
+        let first = say(&mut chat, &orders, &rx, "What is 6 times 7?");
+        let mut call = chunk("", true);
+        call.message.tool_calls = vec![ToolCall {
+            id: Some("call_1".to_string()),
+            function: backend::FunctionCall {
+                name: "calculator".to_string(),
+                arguments: serde_json::json!({"expression": "6*7"}),
+            },
+        }];
+        first.chunk(call).unwrap();
+        chat.reconsile(orders.clone());
+        assert!(chat.locked && chat.running_tools);
+
+        // The calls run off the UI thread, their results go out as soon as they are in.
+        match rx.try_recv().unwrap() {
+            Order::Spawn(job) => job.future.await,
+            order => panic!("unexpected order: {:?}", order),
+        }
+        chat.reconsile(orders.clone());
+        assert!(transcript(&chat).contains(&"42"));
+        let request = match rx.try_recv().unwrap() {
+            Order::Generate(dispatch) => dispatch.request,
+            order => panic!("unexpected order: {:?}", order),
+        };
+        assert_eq!(request.tools.len(), 3);
+        let result = request.messages.last().unwrap();
+        assert!(matches!(result.role, backend::Role::Tool));
+        assert_eq!(result.content, "42");
+        assert_eq!(result.tool_call_id.as_deref(), Some("call_1"));
+        assert!(chat.locked);
+    }
+
+    #[test]
+    fn test_tool_calls_past_the_limit_are_answered() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+        chat.tools = true;
+        let call = |id: usize| ToolCall {
+            id: Some(format!("call_{}", id)),
+            function: backend::FunctionCall {
+                name: "calculator".to_string(),
+                arguments: serde_json::json!({"expression": "1+1"}),
+            },
+        };
+
+        chat.messages
+            .push(Message::new(Author::User, "Keep adding."));
+        for id in 0..MAX_TOOL_ROUNDS {
+            let mut round = Message::new(Author::Bot, "");
+            round.tool_calls = vec![call(id)];
+            let mut result = Message::new(Author::Tool, "2");
+            result.tool_call_id = Some(format!("call_{}", id));
+            chat.messages.extend([round, result]);
         }
+        chat.triggered = true;
+        chat.reconsile(orders.clone());
+        let tx = match rx.try_recv().unwrap() {
+            Order::Generate(dispatch) => dispatch.tx,
+            order => panic!("unexpected order: {:?}", order),
+        };
+        let mut last = chunk("", true);
+        last.message.tool_calls = vec![call(MAX_TOOL_ROUNDS)];
+        tx.chunk(last).unwrap();
+        chat.reconsile(orders.clone());
+
+        assert!(rx.try_recv().is_err());
+        assert!(!chat.locked && chat.error.is_some());
+        let answer = chat.messages.last().unwrap();
+        assert!(answer.author == Author::Tool);
+        assert_eq!(
+            answer.content,
+            "error: gave up after 8 rounds of tool calls"
+        );
+        assert_eq!(answer.tool_call_id.as_deref(), Some("call_8"));
+    }
+
+    #[test]
+    fn test_thinking_stays_out_of_the_context() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        let tx = say(&mut chat, &orders, &rx, "2+2?");
+        let mut reasoning = chunk("", false);
+        reasoning.message.thinking = "Simple sums. ".to_string();
+        tx.chunk(reasoning).unwrap();
+        tx.chunk(chunk("<think>It's 4.</thi", false)).unwrap();
+        tx.chunk(chunk("nk>4", true)).unwrap();
+        chat.reconsile(orders.clone());
+
+        let reply = chat.messages.last().unwrap();
+        assert_eq!(reply.thinking, "Simple sums. It's 4.");
+        assert_eq!(reply.content, "4");
+        assert_eq!(reply.metadata.thinking_tokens, Some((7, false)));
+
+        say(&mut chat, &orders, &rx, "And 3+3?");
+        let request = chat.construct_request();
+        assert_eq!(request.messages[1].content, "4");
+        assert!(request.messages.iter().all(|msg| msg.thinking.is_empty()));
+    }
+
+    #[test]
+    fn test_counts_follow_a_streamed_reply() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        let tx = say(&mut chat, &orders, &rx, "Count to three.");
+        tx.chunk(chunk("One", false)).unwrap();
+        chat.reconsile(orders.clone());
+        let partial = chat.next_tokens();
+        tx.chunk(chunk(", two, three.", true)).unwrap();
+        chat.reconsile(orders.clone());
+        assert_eq!(chat.next_tokens(), partial + 5);
+    }
+
+    #[test]
+    fn test_usage_prefers_server_counts() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+
+        let tx = say(&mut chat, &orders, &rx, "Hi");
+        let mut done = chunk("Hello there", true);
+        done.stats.prompt_eval_count = Some(11);
+        done.stats.eval_count = Some(3);
+        tx.chunk(done).unwrap();
+        chat.reconsile(orders.clone());
+        assert_eq!(
+            chat.usage,
+            tokens::Usage {
+                prompt: 11,
+                completion: 3,
+                estimated: false,
+            }
+        );
+
+        // Without counts, the prompt is what went out: 5 + 6 + 5 for the three messages.
+        let tx = say(&mut chat, &orders, &rx, "Bye");
+        tx.chunk(chunk("Bye now", true)).unwrap();
+        chat.reconsile(orders.clone());
+        assert_eq!(
+            chat.usage,
+            tokens::Usage {
+                prompt: 27,
+                completion: 5,
+                estimated: true,
+            }
+        );
+
+        chat.set_draft("What next?", &[]);
+        assert_eq!(
+            chat.token_line(chat.next_tokens()),
+            "next ~29 · used ~32 (27 in, 5 out) · ~ estimated"
+        );
+        chat.set_draft("/stop", &[]);
+        assert_eq!(chat.next_tokens(), 22);
+        // Counted with the model's own vocabulary, nothing is marked as estimated.
+        let mut exact = Chat::new("gpt", "gpt-4o", Arc::new(Silent));
+        exact.set_draft("What next?", &[]);
+        assert_eq!(
+            exact.token_line(exact.next_tokens()),
+            "next 7 · used 0 (0 in, 0 out)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_older_turns_are_summarized() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::from_spec("mock:echo,delay=0", &Config::default()).unwrap();
+        chat.context = Strategy::Summarize {
+            keep: 1,
+            summarizer: None,
+        };
+        chat.set_system(Some("Be brief."));
+        chat.messages
+            .push(Message::new(Author::User, "Name a colour."));
+        chat.messages.push(Message::new(Author::Bot, "Red."));
+        chat.messages.push(Message::new(Author::User, "Another?"));
+        chat.triggered = true;
+
+        chat.reconsile(orders.clone());
+        assert!(chat.summarizing);
+        // The summary goes out like any request, retried and watched over.
+        match rx.try_recv().unwrap() {
+            Order::Generate(dispatch) => {
+                backend::handle_streaming_request(dispatch, RetryPolicy::from(&Config::default()))
+                    .await
+            }
+            order => panic!("unexpected order: {:?}", order),
+        }
+        chat.reconsile(orders.clone());
+
+        let request = match rx.try_recv().unwrap() {
+            Order::Generate(dispatch) => dispatch.request,
+            order => panic!("unexpected order: {:?}", order),
+        };
+        let contents = request
+            .messages
+            .iter()
+            .map(|msg| msg.content.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            [
+                "Be brief.",
+                "Summary of the earlier conversation:\nUser: Name a colour.\nAssistant: Red.",
+                "Another?",
+            ]
+        );
+        assert_eq!(chat.summary.as_ref().unwrap().covers, 2);
+        assert!(chat
+            .label(chat.next_tokens())
+            .ends_with("<summarize 1> ctx ~23"));
+    }
+
+    #[test]
+    fn test_replies_are_checked_against_the_format() {
+        let (orders, rx) = mpsc::channel();
+        let mut chat = Chat::new("silent", "silent", Arc::new(Silent));
+        chat.format = Some(Format::Schema {
+            name: "person.json".to_string(),
+            schema: serde_json::json!({
+                "type": "object",
+                "properties": { "age": { "type": "integer" } },
+            }),
+        });
+
+        let first = say(&mut chat, &orders, &rx, "Who?");
+        first.chunk(chunk("{\"age\": ", false)).unwrap();
+        first.chunk(chunk("\"ten\"}", true)).unwrap();
+        chat.reconsile(orders.clone());
+        let validation = &chat.messages.last().unwrap().metadata.validation;
+        assert_eq!(
+            validation,
+            &Some(Err("$.age: expected integer, got \"ten\"".to_string()))
+        );
+
+        let second = say(&mut chat, &orders, &rx, "Again");
+        second.chunk(chunk("{\"age\": 10}", true)).unwrap();
+        chat.reconsile(orders.clone());
+        assert_eq!(
+            chat.messages.last().unwrap().metadata.validation,
+            Some(Ok(()))
+        );
     }
 }
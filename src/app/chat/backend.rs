@@ -1,51 +1,714 @@
 use serde::{Deserialize, Serialize};
-use std::sync::mpsc;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
+pub use self::scheduler::Scheduler;
+
+use super::ChatId;
+use crate::config::Config;
 use crate::logging::footstones::*;
 
-#[derive(Debug, Clone, Serialize)]
+pub mod anthropic;
+pub mod mock;
+mod ndjson;
+pub mod ollama;
+pub mod openai;
+pub mod replay;
+mod scheduler;
+mod sse;
+#[cfg(test)]
+mod stub;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A provider capable of streaming a chat completion.
+///
+/// Implementations translate a [`ChatRequest`] into whatever wire protocol the provider speaks
+/// and push every decoded chunk into `tx` as a [`ChatResponse`], the last one having `done` set.
+/// Failures are returned rather than sent, [`handle_streaming_request`] forwards them to the chat.
+pub trait ChatBackend: std::fmt::Debug + Send + Sync {
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        tx: &'a Responder,
+    ) -> BoxFuture<'a, Result<(), BackendError>>;
+
+    /// The server requests go to; the [`Scheduler`] limits concurrency per endpoint.
+    fn endpoint(&self) -> &str;
+}
+
+/// Identifies one dispatched request, so a chat can tell its current turn apart from leftovers.
+pub type RequestId = u64;
+
+/// What a chat receives from the task serving its request.
+// Nearly every update is a chunk, boxing them would only add an allocation to each.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Update {
+    Chunk(ChatResponse),
+    /// The previous attempt failed before producing anything, `attempt` of `retries` is next.
+    Retrying {
+        id: RequestId,
+        attempt: u32,
+        retries: u32,
+    },
+    Failed(RequestId, BackendError),
+    /// Waiting for the endpoint to free up, `position` 1 is next in line.
+    Queued {
+        id: RequestId,
+        position: usize,
+    },
+    /// Left the queue and is now being sent, at `at`.
+    Dispatched {
+        id: RequestId,
+        at: Instant,
+    },
+}
+
+impl Update {
+    /// The request this update belongs to.
+    pub fn id(&self) -> RequestId {
+        match self {
+            Update::Chunk(response) => response.id,
+            Update::Retrying { id, .. } => *id,
+            Update::Failed(id, _) => *id,
+            Update::Queued { id, .. } => *id,
+            Update::Dispatched { id, .. } => *id,
+        }
+    }
+}
+
+/// The sending half of a chat's update channel, as handed to backends. Everything sent through it
+/// is stamped with the id of the request being served.
+#[derive(Debug, Clone)]
+pub struct Responder {
+    tx: mpsc::Sender<Update>,
+    id: RequestId,
+    sent: Arc<AtomicUsize>,
+    /// Also keeps a copy of every chunk, while recording.
+    tape: Option<Arc<Mutex<replay::Tape>>>,
+}
+
+impl Responder {
+    pub fn new(tx: mpsc::Sender<Update>, id: RequestId) -> Self {
+        Self {
+            tx,
+            id,
+            sent: Arc::default(),
+            tape: None,
+        }
+    }
+
+    /// A responder that also copies every chunk onto `tape`.
+    fn recording(&self, tape: Arc<Mutex<replay::Tape>>) -> Self {
+        Self {
+            tape: Some(tape),
+            ..self.clone()
+        }
+    }
+
+    /// Number of chunks forwarded so far, shared between clones.
+    pub fn sent(&self) -> usize {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn chunk(&self, mut response: ChatResponse) -> Result<(), BackendError> {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        response.id = self.id;
+        response.received_at = Some(Instant::now());
+        if let Some(tape) = &self.tape {
+            tape.lock().unwrap().push(&response);
+        }
+        self.tx
+            .send(Update::Chunk(response))
+            .map_err(|_| BackendError::Disconnected)
+    }
+
+    pub fn fail(&self, error: BackendError) {
+        let _ = self.tx.send(Update::Failed(self.id, error));
+    }
+
+    fn retrying(&self, attempt: u32, retries: u32) {
+        let _ = self.tx.send(Update::Retrying {
+            id: self.id,
+            attempt,
+            retries,
+        });
+    }
+
+    fn queued(&self, position: usize) {
+        let _ = self.tx.send(Update::Queued {
+            id: self.id,
+            position,
+        });
+    }
+
+    fn dispatched(&self) {
+        let _ = self.tx.send(Update::Dispatched {
+            id: self.id,
+            at: Instant::now(),
+        });
+    }
+}
+
+/// Turns a non-2xx response into [`BackendError::Status`], keeping the body around since that is
+/// where providers explain what went wrong.
+pub async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, BackendError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    Err(BackendError::Status { status, body })
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    Http(reqwest::Error),
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    Decode(serde_json::Error),
+    /// The provider answered, but with an error of its own.
+    Provider(String),
+    Timeout(String),
+    Disconnected,
+}
+
+impl BackendError {
+    /// Whether trying the same request again has a chance of going differently.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            BackendError::Http(e) => e.is_connect() || e.is_timeout(),
+            BackendError::Status { status, .. } => {
+                status.is_server_error() || *status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            BackendError::Timeout(_) => true,
+            BackendError::Decode(_) | BackendError::Provider(_) | BackendError::Disconnected => {
+                false
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::Http(e) => write!(f, "request failed: {}", e),
+            BackendError::Status { status, body } if body.trim().is_empty() => {
+                write!(f, "HTTP {}", status)
+            }
+            BackendError::Status { status, body } => write!(f, "HTTP {}: {}", status, body.trim()),
+            BackendError::Decode(e) => write!(f, "malformed response: {}", e),
+            BackendError::Provider(e) => write!(f, "provider error: {}", e),
+            BackendError::Timeout(e) => write!(f, "timed out: {}", e),
+            BackendError::Disconnected => write!(f, "chat is no longer listening"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for BackendError {
+    fn from(value: reqwest::Error) -> Self {
+        BackendError::Http(value)
+    }
+}
+
+impl From<serde_json::Error> for BackendError {
+    fn from(value: serde_json::Error) -> Self {
+        BackendError::Decode(value)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct ChatRequest {
+    #[serde(skip)]
+    pub id: RequestId,
     pub model: String,
     pub messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Options::is_empty")]
+    pub options: Options,
+    /// Functions the model may ask to have called instead of answering.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
+    /// Either `"json"` or a JSON schema the reply has to conform to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A function the model may call, in the shape Ollama and OpenAI describe them.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub function: FunctionSpec,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionSpec {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// JSON schema of the arguments object.
+    pub parameters: serde_json::Value,
+}
+
+impl Tool {
+    pub fn function(
+        name: &'static str,
+        description: &'static str,
+        parameters: serde_json::Value,
+    ) -> Self {
+        Self {
+            kind: "function",
+            function: FunctionSpec {
+                name,
+                description,
+                parameters,
+            },
+        }
+    }
+}
+
+/// Generation options under their Ollama names, other backends translate the ones they support.
+pub type Options = BTreeMap<String, serde_json::Value>;
+
+/// Validates the `/set` arguments for option `key`. `stop` takes any number of sequences, every
+/// other option exactly one number.
+pub fn parse_option(key: &str, values: &[String]) -> Result<serde_json::Value, String> {
+    let single = || match values {
+        [value] => Ok(value.as_str()),
+        _ => Err(format!("{} takes exactly one value", key)),
+    };
+
+    match key {
+        "temperature" | "top_p" => {
+            let value = single()?;
+            value
+                .parse::<f64>()
+                .map(serde_json::Value::from)
+                .map_err(|_| format!("{} must be a number, got {}", key, value))
+        }
+        "num_ctx" | "num_predict" | "seed" => {
+            let value = single()?;
+            value
+                .parse::<i64>()
+                .map(serde_json::Value::from)
+                .map_err(|_| format!("{} must be an integer, got {}", key, value))
+        }
+        "stop" if !values.is_empty() => Ok(serde_json::Value::from(values.to_vec())),
+        "stop" => Err("stop takes at least one sequence".to_string()),
+        _ => Err(format!(
+            "Unknown option {}, expected one of temperature, top_p, num_ctx, num_predict, seed, stop",
+            key
+        )),
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Message {
     pub role: Role,
     pub content: String,
+    /// Reasoning streamed apart from the answer, by Ollama's `think` and the like. Never sent.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub thinking: String,
+    /// Base64-encoded images, in the shape Ollama expects them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+    /// Calls the assistant asked for instead of, or along with, answering.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// For [`Role::Tool`] messages, the call this is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    /// Assigned by OpenAI and Anthropic to tie results to calls; Ollama goes by order.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+/// A tool call being streamed piecemeal, the arguments arrive as fragments of a JSON string.
+#[derive(Default)]
+struct PartialCall {
+    id: Option<String>,
+    name: String,
+    arguments: String,
+}
+
+impl PartialCall {
+    fn finish(self) -> Result<ToolCall, BackendError> {
+        let arguments = match self.arguments.trim() {
+            "" => serde_json::Value::Object(Default::default()),
+            arguments => serde_json::from_str(arguments)?,
+        };
+        Ok(ToolCall {
+            id: self.id,
+            function: FunctionCall {
+                name: self.name,
+                arguments,
+            },
+        })
+    }
+}
+
+/// Guesses the media type of a base64-encoded image from its magic bytes, which for the common
+/// formats survive encoding as a fixed prefix. Falls back to PNG.
+pub fn image_media_type(image: &str) -> &'static str {
+    match image {
+        _ if image.starts_with("/9j/") => "image/jpeg",
+        _ if image.starts_with("R0lGOD") => "image/gif",
+        _ if image.starts_with("UklGR") => "image/webp",
+        _ => "image/png",
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
+    #[default]
     User,
     Assistant,
+    System,
+    Tool,
 }
 // {"model":"llama3","created_at":"2024-06-09T15:54:01.34414989Z","message":{"role":"assistant","content":"?"},"done":false}
 // {"model":"llama3","created_at":"2024-06-09T15:54:01.426999551Z","message":{"role":"assistant","content":""},"done_reason":"stop","done":true,"total_duration":4600646509,"load_duration":2069650368,"prompt_eval_count":10,"prompt_eval_duration":326180000,"eval_count":26,"eval_duration":2072971000}
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatResponse {
+    /// Set by the [`Responder`], never part of the wire format.
+    #[serde(skip)]
+    pub id: RequestId,
+    /// When the runtime thread got hold of this chunk, also set by the [`Responder`].
+    #[serde(skip)]
+    pub received_at: Option<Instant>,
     pub model: String,
     pub message: Message,
     pub done: bool,
+    #[serde(default)]
+    pub done_reason: Option<String>,
+    #[serde(flatten)]
+    pub stats: Stats,
 }
 
-pub async fn handle_streaming_request((tx, req): (mpsc::Sender<ChatResponse>, ChatRequest)) {
-    info!("Sending chat request: {:?}", req);
-    let mut response = reqwest::Client::new()
-        .post("http://localhost:11434/api/chat")
-        .json(&req)
-        .send()
-        .await
-        .unwrap();
+impl ChatResponse {
+    /// A chunk of assistant text, for backends whose wire format has to be translated.
+    pub fn new(model: String, content: String, done: bool) -> Self {
+        Self {
+            id: 0,
+            received_at: None,
+            model,
+            message: Message {
+                role: Role::Assistant,
+                content,
+                ..Default::default()
+            },
+            done,
+            done_reason: None,
+            stats: Stats::default(),
+        }
+    }
+}
+
+/// Completion statistics, as found on the final chunk. Durations are in nanoseconds and only
+/// reported by Ollama; other backends fill in the token counts they know about.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub total_duration: Option<u64>,
+    pub load_duration: Option<u64>,
+    pub prompt_eval_count: Option<u64>,
+    pub prompt_eval_duration: Option<u64>,
+    pub eval_count: Option<u64>,
+    pub eval_duration: Option<u64>,
+    /// Part of `eval_count` spent reasoning, for the servers that tell.
+    #[serde(default)]
+    pub thinking_count: Option<u64>,
+}
+
+/// Resolves a `/create` argument of the form `[provider:]model[@endpoint]` into the model name
+/// and the backend serving it.
+///
+/// Provider is one of `ollama`, `openai`, `anthropic`, `mock` or `replay`; without a known prefix
+/// the whole spec is an Ollama model, so tags such as `llama3:8b` keep working. See [`mock::Mock`]
+/// for what the model part of a mock spec configures. With `record` on in the config, every
+/// backend but `replay` is wrapped in a [`replay::Recorder`]. Endpoint is either `host[:port]` or
+/// a full base URL, and defaults to the configured Ollama server or the provider's usual one.
+pub fn resolve(spec: &str, config: &Config) -> Result<(String, Arc<dyn ChatBackend>), String> {
+    let (spec_model, endpoint) = match spec.rsplit_once('@') {
+        Some((_, "")) => return Err(format!("Endpoint is required after `@` in `{}`", spec)),
+        Some((model, endpoint)) => (model, Some(endpoint)),
+        None => (spec, None),
+    };
+
+    let (provider, model) = match spec_model.split_once(':') {
+        Some((provider, model))
+            if ["ollama", "openai", "anthropic", "mock", "replay"].contains(&provider) =>
+        {
+            (provider, model)
+        }
+        _ => ("ollama", spec_model),
+    };
+
+    if model.is_empty() {
+        return Err(format!("Model name is required in `{}`", spec));
+    }
+
+    let backend: Arc<dyn ChatBackend> = match provider {
+        "openai" => {
+            let base_url = openai::OpenAi::base_url(
+                endpoint.map(|endpoint| endpoint_url(endpoint, None, "/v1")),
+            );
+            let client = http_client(config, &base_url)?;
+            Arc::new(openai::OpenAi::from_env(client, &base_url))
+        }
+        "anthropic" => {
+            let base_url = anthropic::Anthropic::base_url(
+                endpoint.map(|endpoint| endpoint_url(endpoint, None, "")),
+            );
+            let client = http_client(config, &base_url)?;
+            Arc::new(anthropic::Anthropic::from_env(client, &base_url))
+        }
+        "mock" => Arc::new(mock::Mock::parse(model)?),
+        "replay" => {
+            return Ok((
+                model.to_string(),
+                Arc::new(replay::Replay::new(&config.fixtures)),
+            ))
+        }
+        _ => {
+            let base_url = ollama_url(endpoint, config);
+            let client = http_client(config, &base_url)?;
+            Arc::new(ollama::Ollama::new(client, &base_url))
+        }
+    };
+    let backend: Arc<dyn ChatBackend> = match config.record {
+        true => Arc::new(replay::Recorder::new(backend, &config.fixtures)),
+        false => backend,
+    };
+
+    Ok((model.to_string(), backend))
+}
+
+/// A client for the endpoint at `base_url`, with the headers, proxy and certificates its entry
+/// in the config's `endpoints` asks for.
+pub fn http_client(config: &Config, base_url: &str) -> Result<reqwest::Client, String> {
+    let mut builder =
+        reqwest::Client::builder().connect_timeout(Duration::from_secs(config.connect_timeout));
+
+    if let Some(endpoint) = config.endpoint(base_url) {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in &endpoint.headers {
+            let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| format!("Bad header name {} for {}: {}", name, base_url, e))?;
+            let value = reqwest::header::HeaderValue::from_str(value)
+                .map_err(|e| format!("Bad value of header {} for {}: {}", name, base_url, e))?;
+            headers.insert(name, value);
+        }
+        if let Some(var) = &endpoint.token_env {
+            let token = std::env::var(var).map_err(|_| {
+                format!(
+                    "Token for {} expected in ${}, which is unset",
+                    base_url, var
+                )
+            })?;
+            let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))
+                .map_err(|e| format!("Bad token in ${}: {}", var, e))?;
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        builder = builder.default_headers(headers);
 
-    while let Some(chunk) = response.chunk().await.unwrap() {
-        let chat_response: ChatResponse = serde_json::from_slice(&chunk).unwrap();
-        match tx.send(chat_response) {
-            Ok(_) => {}
+        if let Some(proxy) = &endpoint.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| format!("Bad proxy {} for {}: {}", proxy, base_url, e))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &endpoint.ca_bundle {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Failed to read CA bundle {}: {}", path, e))?;
+            let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("Bad CA bundle {}: {}", path, e))?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+    }
+
+    builder
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))
+}
+
+/// Base URL of the Ollama server at `endpoint`, or of the configured one.
+pub fn ollama_url(endpoint: Option<&str>, config: &Config) -> String {
+    match endpoint {
+        Some(endpoint) => endpoint_url(endpoint, Some(ollama::Ollama::DEFAULT_PORT), ""),
+        None => config.base_url(),
+    }
+}
+
+/// Expands a bare `host[:port]` into an HTTP base URL ending in `path`; full URLs are kept as is.
+fn endpoint_url(endpoint: &str, default_port: Option<u16>, path: &str) -> String {
+    if endpoint.contains("://") {
+        return endpoint.to_string();
+    }
+
+    match default_port {
+        Some(port) if !endpoint.contains(':') => format!("http://{}:{}{}", endpoint, port, path),
+        _ => format!("http://{}{}", endpoint, path),
+    }
+}
+
+/// Work handed from the UI thread to the runtime thread.
+#[derive(Debug)]
+pub enum Order {
+    Generate(Dispatch),
+    /// Abort whatever request is in flight for the chat, dropping its HTTP stream.
+    Stop(ChatId),
+    /// Anything else that needs the runtime, reporting back through its own channel.
+    Spawn(Job),
+}
+
+/// A fire-and-forget future, such as listing the models of an endpoint.
+pub struct Job {
+    /// The endpoint it talks to, whose requests it waits in line with; `None` for local work.
+    pub endpoint: Option<String>,
+    pub future: BoxFuture<'static, ()>,
+}
+
+impl Job {
+    /// Work that doesn't talk to any endpoint, run right away.
+    pub fn local(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self {
+            endpoint: None,
+            future: Box::pin(future),
+        }
+    }
+
+    /// Work against `endpoint`, counted towards its concurrency limit like any request.
+    pub fn on(endpoint: String, future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self {
+            endpoint: Some(endpoint),
+            future: Box::pin(future),
+        }
+    }
+}
+
+impl std::fmt::Debug for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Job({:?})", self.endpoint)
+    }
+}
+
+/// A request bound to the backend that should serve it and the channel its chunks go to.
+#[derive(Debug)]
+pub struct Dispatch {
+    pub chat: ChatId,
+    pub backend: Arc<dyn ChatBackend>,
+    pub tx: Responder,
+    pub request: ChatRequest,
+}
+
+/// How long the runtime waits on a backend, and how hard it tries again, see [`Config`].
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub first_token_timeout: Duration,
+    pub stall_timeout: Duration,
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+impl From<&Config> for RetryPolicy {
+    fn from(config: &Config) -> Self {
+        Self {
+            first_token_timeout: Duration::from_secs(config.first_token_timeout),
+            stall_timeout: Duration::from_secs(config.stall_timeout),
+            retries: config.retries,
+            backoff: Duration::from_millis(config.retry_backoff),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Longest wait before a retry, however many came before it.
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    /// Wait before retry number `attempt`, counted from 1, doubling up to [`Self::MAX_BACKOFF`].
+    fn backoff(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(Self::MAX_BACKOFF)
+    }
+}
+
+/// Resolves once `tx` has gone quiet for longer than the policy allows.
+async fn watchdog(tx: &Responder, policy: &RetryPolicy) -> BackendError {
+    let mut seen = tx.sent();
+    let mut since = Instant::now();
+
+    loop {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        if tx.sent() != seen {
+            seen = tx.sent();
+            since = Instant::now();
+        } else if seen == 0 && since.elapsed() > policy.first_token_timeout {
+            return BackendError::Timeout(format!(
+                "no response within {:?}",
+                policy.first_token_timeout
+            ));
+        } else if seen != 0 && since.elapsed() > policy.stall_timeout {
+            return BackendError::Timeout(format!("stalled for {:?}", policy.stall_timeout));
+        }
+    }
+}
+
+pub async fn handle_streaming_request(dispatch: Dispatch, policy: RetryPolicy) {
+    let Dispatch {
+        chat: _,
+        backend,
+        tx,
+        request,
+    } = dispatch;
+
+    info!("Sending chat request: {:?}", request);
+
+    let mut attempt = 0;
+    loop {
+        let outcome = tokio::select! {
+            outcome = backend.stream(request.clone(), &tx) => outcome,
+            error = watchdog(&tx, &policy) => Err(error),
+        };
+
+        match outcome {
+            Ok(()) | Err(BackendError::Disconnected) => return,
+            // Only safe to retry as long as the chat hasn't seen any part of the answer.
+            Err(e) if e.is_transient() && tx.sent() == 0 && attempt < policy.retries => {
+                attempt += 1;
+                warn!(
+                    "Chat request failed, retrying ({}/{}): {}",
+                    attempt, policy.retries, e
+                );
+                tx.retrying(attempt, policy.retries);
+                tokio::time::sleep(policy.backoff(attempt)).await;
+            }
             Err(e) => {
-                error!("Failed to send chat response: {:?}", e);
+                error!("Chat request failed: {}", e);
+                tx.fail(e);
                 return;
             }
         }
@@ -56,25 +719,275 @@ pub async fn handle_streaming_request((tx, req): (mpsc::Sender<ChatResponse>, Ch
 mod tests {
     use super::*;
 
+    fn resolved(spec: &str) -> (String, String) {
+        let (model, backend) = resolve(spec, &Config::default()).unwrap();
+        (model, format!("{:?}", backend))
+    }
+
+    #[test]
+    fn test_parse_option() {
+        let args = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            parse_option("temperature", &args(&["0.2"])),
+            Ok(serde_json::json!(0.2))
+        );
+        assert_eq!(
+            parse_option("seed", &args(&["42"])),
+            Ok(serde_json::json!(42))
+        );
+        assert_eq!(
+            parse_option("stop", &args(&["</s>", "\n\n"])),
+            Ok(serde_json::json!(["</s>", "\n\n"]))
+        );
+        assert!(parse_option("num_ctx", &args(&["2k"])).is_err());
+        assert!(parse_option("top_p", &args(&["0.9", "0.8"])).is_err());
+        assert!(parse_option("temprature", &args(&["0.2"])).is_err());
+    }
+
+    #[test]
+    fn test_resolve_endpoints() {
+        let (model, backend) = resolved("llama3:8b");
+        assert_eq!(model, "llama3:8b");
+        assert!(backend.contains("\"http://localhost:11434\""));
+
+        let (model, backend) = resolved("llama3@gpu-box:11500");
+        assert_eq!(model, "llama3");
+        assert!(backend.contains("\"http://gpu-box:11500\""));
+
+        let (_, backend) = resolved("ollama:llama3@gpu-box");
+        assert!(backend.contains("\"http://gpu-box:11434\""));
+
+        let (model, backend) = resolved("openai:qwen2@box:8000");
+        assert_eq!(model, "qwen2");
+        assert!(backend.starts_with("OpenAi"));
+        assert!(backend.contains("\"http://box:8000/v1\""));
+
+        let (_, backend) = resolved("anthropic:claude@https://proxy.internal");
+        assert!(backend.contains("\"https://proxy.internal\""));
+
+        assert!(resolve("llama3@", &Config::default()).is_err());
+        assert!(resolve("openai:@box", &Config::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_settings() {
+        use std::io::{BufRead, BufReader, Write};
+
+        // Plays the proxy, answering whatever it is asked with a finished reply.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let head = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            let head = reader
+                .lines()
+                .map(Result::unwrap)
+                .take_while(|line| !line.is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let body = "{\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true}\n";
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: application/x-ndjson\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+            head.to_lowercase()
+        });
+
+        std::env::set_var("MULTI_AI_TEST_TOKEN", "s3cret");
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "endpoints": {
+                "ollama.internal": {
+                    "headers": {"X-Team": "evals"},
+                    "token_env": "MULTI_AI_TEST_TOKEN",
+                    "proxy": proxy,
+                },
+                "https://ollama.internal": {"token_env": "MULTI_AI_UNSET_TOKEN"},
+            },
+        }))
+        .unwrap();
+
+        assert!(config.endpoint("http://localhost:11434").is_none());
+        assert!(config
+            .endpoint("https://ollama.internal/")
+            .unwrap()
+            .proxy
+            .is_none());
+        assert!(resolve("llama3@https://ollama.internal", &config).is_err());
+
+        let (_, backend) = resolve("llama3@ollama.internal", &config).unwrap();
+        let (tx, _rx) = mpsc::channel();
+        let request = ChatRequest {
+            model: "llama3".to_string(),
+            ..Default::default()
+        };
+        backend
+            .stream(request, &Responder::new(tx, 0))
+            .await
+            .unwrap();
+
+        let head = head.join().unwrap();
+        assert!(head.starts_with("post http://ollama.internal:11434/api/chat"));
+        assert!(head.contains("x-team: evals"));
+        assert!(head.contains("authorization: bearer s3cret"));
+    }
+
+    #[test]
+    fn test_endpoint_matching() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "endpoints": {
+                "gpu-box": {"token_env": "ANY"},
+                "https://secure-box": {"token_env": "HTTPS"},
+                "gpu-box:8080/v1": {"token_env": "V1"},
+            },
+        }))
+        .unwrap();
+        let token = |url: &str| config.endpoint(url).and_then(|e| e.token_env.as_deref());
+
+        assert_eq!(token("http://gpu-box:11434"), Some("ANY"));
+        assert_eq!(token("https://GPU-BOX/"), Some("ANY"));
+        assert_eq!(token("http://gpu-box:8080/v1/"), Some("V1"));
+        assert_eq!(token("https://secure-box:443/api"), Some("HTTPS"));
+
+        // Neighbouring hosts, and tokens meant for TLS, don't leak.
+        assert_eq!(token("http://gpu-box2:11434"), None);
+        assert_eq!(token("http://gpu-box.attacker.net"), None);
+        assert_eq!(token("http://secure-box"), None);
+        assert_eq!(token("https://secure-box:8443"), None);
+        assert_eq!(token("http://gpu-box:8080/v10"), Some("ANY"));
+    }
+
+    fn policy(first_token_timeout: Duration, retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            first_token_timeout,
+            stall_timeout: first_token_timeout,
+            retries,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    async fn run(url: &str, policy: RetryPolicy) -> Vec<Update> {
+        let (tx, rx) = mpsc::channel();
+        let dispatch = Dispatch {
+            chat: 0,
+            backend: Arc::new(ollama::Ollama::new(reqwest::Client::new(), url)),
+            tx: Responder::new(tx, 7),
+            request: ChatRequest {
+                id: 7,
+                model: "llama3".to_string(),
+                ..Default::default()
+            },
+        };
+        handle_streaming_request(dispatch, policy).await;
+        rx.try_iter().collect()
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures_before_first_token() {
+        let (url, _server) = stub::serve_each(vec![
+            ("503 Service Unavailable", "text/plain", vec![]),
+            (
+                "200 OK",
+                "application/x-ndjson",
+                vec!["{\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"Hi\"},\"done\":true}\n".to_string()],
+            ),
+        ]);
+
+        let updates = run(&url, policy(Duration::from_secs(5), 2)).await;
+
+        assert!(matches!(
+            updates[0],
+            Update::Retrying {
+                id: 7,
+                attempt: 1,
+                retries: 2
+            }
+        ));
+        assert!(matches!(&updates[1], Update::Chunk(chunk) if chunk.id == 7 && chunk.done));
+        assert_eq!(updates.len(), 2);
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy {
+            backoff: Duration::from_millis(500),
+            ..policy(Duration::from_secs(5), 40)
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(3), Duration::from_secs(2));
+        assert_eq!(policy.backoff(33), RetryPolicy::MAX_BACKOFF);
+        assert_eq!(policy.backoff(u32::MAX), RetryPolicy::MAX_BACKOFF);
+
+        let config = Config {
+            retries: 40,
+            ..Config::default()
+        };
+        assert!(config.checked().is_err());
+        assert!(Config::default().checked().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_errors_are_not_retried() {
+        let (url, _server) = stub::serve("404 Not Found", "text/plain", vec![]);
+
+        let updates = run(&url, policy(Duration::from_secs(5), 2)).await;
+
+        assert!(matches!(
+            &updates[..],
+            [Update::Failed(7, BackendError::Status { status, .. })] if status.as_u16() == 404
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_first_token_timeout() {
+        // Accepts connections into the backlog but never answers them.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let updates = run(&url, policy(Duration::from_millis(200), 1)).await;
+
+        assert!(matches!(updates[0], Update::Retrying { attempt: 1, .. }));
+        assert!(matches!(
+            &updates[1..],
+            [Update::Failed(7, BackendError::Timeout(_))]
+        ));
+    }
+
     #[tokio::test]
     async fn test_chat_request() {
+        let (model, backend) = resolve("mock:echo,delay=0", &Config::default()).unwrap();
         let chat_request = ChatRequest {
-            model: "llama3".to_string(),
+            model,
             messages: vec![Message {
                 role: Role::User,
-                content: "Hello".to_string(),
+                content: "Hello there".to_string(),
+                ..Default::default()
             }],
+            ..Default::default()
         };
 
-        let mut response = reqwest::Client::new()
-            .post("http://localhost:11434/api/chat")
-            .json(&chat_request)
-            .send()
-            .await
-            .unwrap();
+        let (tx, rx) = mpsc::channel();
+        handle_streaming_request(
+            Dispatch {
+                chat: 0,
+                backend,
+                tx: Responder::new(tx, 0),
+                request: chat_request,
+            },
+            policy(Duration::from_secs(5), 0),
+        )
+        .await;
 
-        while let Some(chunk) = response.chunk().await.unwrap() {
-            let chat_response: ChatResponse = serde_json::from_slice(&chunk).unwrap();
-        }
+        let chat_responses = stub::chunks(&rx);
+        let content = chat_responses
+            .iter()
+            .map(|c| c.message.content.as_str())
+            .collect::<String>();
+        assert_eq!(content, "Hello there");
+        assert!(chat_responses.last().unwrap().done);
     }
 }
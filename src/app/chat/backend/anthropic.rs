@@ -0,0 +1,593 @@
+use serde::{Deserialize, Serialize};
+
+use super::sse::SseDecoder;
+use super::{
+    check_status, image_media_type, BackendError, BoxFuture, ChatBackend, ChatRequest,
+    ChatResponse, Message, PartialCall, Responder, Role, Stats, Tool,
+};
+
+/// Talks to the Anthropic Messages API (`/v1/messages`).
+#[derive(Debug)]
+pub struct Anthropic {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl Anthropic {
+    pub const DEFAULT_BASE_URL: &'static str = "https://api.anthropic.com";
+    pub const VERSION: &'static str = "2023-06-01";
+    /// The Messages API refuses requests without `max_tokens`.
+    pub const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+    pub fn new(client: reqwest::Client, base_url: &str, api_key: Option<String>) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+        }
+    }
+
+    /// `base_url` if given, else `ANTHROPIC_BASE_URL` from the environment or the default.
+    pub fn base_url(base_url: Option<String>) -> String {
+        base_url
+            .or_else(|| std::env::var("ANTHROPIC_BASE_URL").ok())
+            .unwrap_or_else(|| Self::DEFAULT_BASE_URL.to_string())
+    }
+
+    /// Picks up `ANTHROPIC_API_KEY` from the environment.
+    pub fn from_env(client: reqwest::Client, base_url: &str) -> Self {
+        Self::new(client, base_url, std::env::var("ANTHROPIC_API_KEY").ok())
+    }
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<InputMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition<'a>>,
+}
+
+#[derive(Serialize)]
+struct ToolDefinition<'a> {
+    name: &'a str,
+    description: &'a str,
+    input_schema: &'a serde_json::Value,
+}
+
+impl<'a> From<&'a Tool> for ToolDefinition<'a> {
+    fn from(tool: &'a Tool) -> Self {
+        Self {
+            name: tool.function.name,
+            description: tool.function.description,
+            input_schema: &tool.function.parameters,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct InputMessage<'a> {
+    role: &'a Role,
+    content: Content<'a>,
+}
+
+/// Plain text, unless there are images or tool calls, which only fit in a list of content
+/// blocks.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Content<'a> {
+    Text(&'a str),
+    Blocks(Vec<Block<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Block<'a> {
+    Text {
+        text: &'a str,
+    },
+    Image {
+        source: ImageSource<'a>,
+    },
+    ToolUse {
+        id: &'a str,
+        name: &'a str,
+        input: &'a serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: &'a str,
+        content: &'a str,
+    },
+}
+
+#[derive(Serialize)]
+struct ImageSource<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    media_type: &'static str,
+    data: &'a str,
+}
+
+impl<'a> From<&'a Message> for InputMessage<'a> {
+    /// Images go before the text, which is where the Messages API docs recommend putting them.
+    /// Tool results are sent back by the user, as `tool_result` blocks.
+    fn from(message: &'a Message) -> Self {
+        let content = if let Role::Tool = message.role {
+            Content::Blocks(vec![Block::ToolResult {
+                tool_use_id: message.tool_call_id.as_deref().unwrap_or_default(),
+                content: &message.content,
+            }])
+        } else if !message.tool_calls.is_empty() {
+            let text = (!message.content.is_empty()).then_some(Block::Text {
+                text: &message.content,
+            });
+            let calls = message.tool_calls.iter().map(|call| Block::ToolUse {
+                id: call.id.as_deref().unwrap_or_default(),
+                name: &call.function.name,
+                input: &call.function.arguments,
+            });
+            Content::Blocks(text.into_iter().chain(calls).collect())
+        } else if message.images.is_empty() {
+            Content::Text(&message.content)
+        } else {
+            let images = message.images.iter().map(|image| Block::Image {
+                source: ImageSource {
+                    kind: "base64",
+                    media_type: image_media_type(image),
+                    data: image,
+                },
+            });
+            Content::Blocks(
+                images
+                    .chain(std::iter::once(Block::Text {
+                        text: &message.content,
+                    }))
+                    .collect(),
+            )
+        };
+
+        let role = match message.role {
+            Role::Tool => &Role::User,
+            ref role => role,
+        };
+        Self { role, content }
+    }
+}
+
+impl<'a> MessagesRequest<'a> {
+    /// System prompts are not part of the conversation in the Messages API, they are lifted into
+    /// the top-level `system` field instead. `num_predict` maps onto `max_tokens`, `seed` and
+    /// `num_ctx` have no equivalent, neither has `format`. Consecutive tool results are merged into
+    /// one user message, since the roles have to alternate.
+    fn new(request: &'a ChatRequest) -> Self {
+        let (system, messages): (Vec<_>, Vec<_>) = request
+            .messages
+            .iter()
+            .partition(|msg| matches!(msg.role, Role::System));
+
+        let system = system
+            .iter()
+            .map(|msg| msg.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+
+        Self {
+            model: &request.model,
+            max_tokens: request
+                .options
+                .get("num_predict")
+                .and_then(serde_json::Value::as_u64)
+                .unwrap_or(Anthropic::DEFAULT_MAX_TOKENS),
+            system: (!system.is_empty()).then_some(system),
+            messages: messages.into_iter().map(InputMessage::from).fold(
+                Vec::new(),
+                |mut merged, message| {
+                    match (merged.last_mut(), message.content) {
+                        (
+                            Some(InputMessage {
+                                content: Content::Blocks(blocks),
+                                ..
+                            }),
+                            Content::Blocks(more),
+                        ) if matches!(blocks.last(), Some(Block::ToolResult { .. }))
+                            && matches!(more.first(), Some(Block::ToolResult { .. })) =>
+                        {
+                            blocks.extend(more)
+                        }
+                        (_, content) => merged.push(InputMessage {
+                            role: message.role,
+                            content,
+                        }),
+                    }
+                    merged
+                },
+            ),
+            stream: true,
+            temperature: request.options.get("temperature"),
+            top_p: request.options.get("top_p"),
+            stop_sequences: request.options.get("stop"),
+            tools: request.tools.iter().map(ToolDefinition::from).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MessageStart {
+    message: StartedMessage,
+}
+
+#[derive(Deserialize)]
+struct StartedMessage {
+    model: String,
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+struct MessageDelta {
+    delta: StopDelta,
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+struct StopDelta {
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: Option<u64>,
+    #[serde(default)]
+    output_tokens: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ContentBlockStart {
+    index: usize,
+    content_block: StartedBlock,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum StartedBlock {
+    #[serde(rename = "tool_use")]
+    ToolUse { id: String, name: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ContentBlockDelta {
+    index: usize,
+    delta: BlockDelta,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum BlockDelta {
+    #[serde(rename = "text_delta")]
+    Text { text: String },
+    #[serde(rename = "input_json_delta")]
+    InputJson { partial_json: String },
+    #[serde(rename = "thinking_delta")]
+    Thinking { thinking: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct ErrorEvent {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+impl ChatBackend for Anthropic {
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        tx: &'a Responder,
+    ) -> BoxFuture<'a, Result<(), BackendError>> {
+        Box::pin(async move {
+            let mut builder = self
+                .client
+                .post(format!("{}/v1/messages", self.base_url))
+                .header("anthropic-version", Self::VERSION)
+                .json(&MessagesRequest::new(&request));
+            if let Some(key) = &self.api_key {
+                builder = builder.header("x-api-key", key);
+            }
+            let mut response = check_status(builder.send().await?).await?;
+
+            let mut model = request.model.clone();
+            let mut stats = Stats::default();
+            let mut done_reason = None;
+            // Tool use blocks by index, their input arrives as fragments of a JSON string.
+            let mut calls = std::collections::BTreeMap::new();
+            let mut decoder = SseDecoder::new();
+            while let Some(chunk) = response.chunk().await? {
+                for event in decoder.feed(&chunk) {
+                    let (content, done) = match event.event.as_deref() {
+                        Some("message_start") => {
+                            let start: MessageStart = serde_json::from_str(&event.data)?;
+                            model = start.message.model;
+                            stats.prompt_eval_count = start.message.usage.input_tokens;
+                            continue;
+                        }
+                        Some("content_block_start") => {
+                            let start: ContentBlockStart = serde_json::from_str(&event.data)?;
+                            if let StartedBlock::ToolUse { id, name } = start.content_block {
+                                let call = PartialCall {
+                                    id: Some(id),
+                                    name,
+                                    arguments: String::new(),
+                                };
+                                calls.insert(start.index, call);
+                            }
+                            continue;
+                        }
+                        Some("content_block_delta") => {
+                            let delta: ContentBlockDelta = serde_json::from_str(&event.data)?;
+                            match delta.delta {
+                                BlockDelta::Text { text } => (text, false),
+                                BlockDelta::InputJson { partial_json } => {
+                                    if let Some(call) = calls.get_mut(&delta.index) {
+                                        call.arguments += &partial_json;
+                                    }
+                                    continue;
+                                }
+                                BlockDelta::Thinking { thinking } => {
+                                    let mut chunk =
+                                        ChatResponse::new(model.clone(), String::new(), false);
+                                    chunk.message.thinking = thinking;
+                                    tx.chunk(chunk)?;
+                                    continue;
+                                }
+                                BlockDelta::Other => continue,
+                            }
+                        }
+                        Some("message_delta") => {
+                            let delta: MessageDelta = serde_json::from_str(&event.data)?;
+                            done_reason = delta.delta.stop_reason;
+                            stats.eval_count = delta.usage.output_tokens;
+                            continue;
+                        }
+                        Some("message_stop") => (String::new(), true),
+                        Some("error") => {
+                            let error: ErrorEvent = serde_json::from_str(&event.data)?;
+                            return Err(BackendError::Provider(format!(
+                                "{}: {}",
+                                error.error.kind, error.error.message
+                            )));
+                        }
+                        // ping and content_block_stop carry nothing of interest.
+                        _ => continue,
+                    };
+
+                    let mut chunk = ChatResponse::new(model.clone(), content, done);
+                    if done {
+                        chunk.message.tool_calls = std::mem::take(&mut calls)
+                            .into_values()
+                            .map(PartialCall::finish)
+                            .collect::<Result<_, _>>()?;
+                        chunk.done_reason = done_reason.take();
+                        chunk.stats = std::mem::take(&mut stats);
+                        return tx.chunk(chunk);
+                    }
+                    tx.chunk(chunk)?;
+                }
+            }
+
+            Err(BackendError::Provider(
+                "stream ended before message_stop".to_string(),
+            ))
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.base_url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::backend::{stub, FunctionCall, ToolCall};
+    use std::sync::mpsc;
+
+    const RECORDED: &str = concat!(
+        "event: message_start\n",
+        "data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"type\":\"message\",\"role\":\"assistant\",\"content\":[],\"model\":\"claude-3-haiku-20240307\",\"stop_reason\":null,\"usage\":{\"input_tokens\":12,\"output_tokens\":1}}}\n\n",
+        "event: content_block_start\n",
+        "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+        "event: ping\n",
+        "data: {\"type\":\"ping\"}\n\n",
+        "event: content_block_delta\n",
+        "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Ahoy\"}}\n\n",
+        "event: content_block_delta\n",
+        "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\" there\"}}\n\n",
+        "event: content_block_stop\n",
+        "data: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+        "event: message_delta\n",
+        "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\",\"stop_sequence\":null},\"usage\":{\"output_tokens\":3}}\n\n",
+        "event: message_stop\n",
+        "data: {\"type\":\"message_stop\"}\n\n",
+    );
+
+    fn request() -> ChatRequest {
+        ChatRequest {
+            model: "claude-3-haiku-20240307".to_string(),
+            messages: vec![
+                Message {
+                    role: Role::System,
+                    content: "Talk like a pirate.".to_string(),
+                    ..Default::default()
+                },
+                Message {
+                    role: Role::User,
+                    content: "Hi".to_string(),
+                    images: vec!["iVBORw0KGgo".to_string()],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replays_recorded_stream() {
+        // Split the recording at awkward offsets so events straddle network chunks.
+        let parts = RECORDED
+            .as_bytes()
+            .chunks(37)
+            .map(|part| String::from_utf8(part.to_vec()).unwrap())
+            .collect();
+        let (url, server) = stub::serve("200 OK", "text/event-stream", parts);
+
+        let backend = Anthropic::new(reqwest::Client::new(), &url, Some("test-key".to_string()));
+        let (tx, rx) = mpsc::channel();
+        backend
+            .stream(request(), &Responder::new(tx, 0))
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["system"], "Talk like a pirate.");
+        assert_eq!(body["max_tokens"], Anthropic::DEFAULT_MAX_TOKENS);
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert_eq!(
+            body["messages"][0]["content"][0]["source"]["media_type"],
+            "image/png"
+        );
+        assert_eq!(body["messages"][0]["content"][1]["text"], "Hi");
+
+        let chunks = stub::chunks(&rx);
+        let content = chunks
+            .iter()
+            .map(|c| c.message.content.as_str())
+            .collect::<String>();
+        assert_eq!(content, "Ahoy there");
+
+        let last = chunks.last().unwrap();
+        assert!(last.done);
+        assert_eq!(last.done_reason.as_deref(), Some("end_turn"));
+        assert_eq!(last.stats.prompt_eval_count, Some(12));
+        assert_eq!(last.stats.eval_count, Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_error_event_is_reported() {
+        let (url, _server) = stub::serve(
+            "200 OK",
+            "text/event-stream",
+            vec![concat!(
+                "event: error\n",
+                "data: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n",
+            )
+            .to_string()],
+        );
+
+        let backend = Anthropic::new(reqwest::Client::new(), &url, None);
+        let (tx, _rx) = mpsc::channel();
+        let error = backend
+            .stream(request(), &Responder::new(tx, 0))
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(error, BackendError::Provider(msg) if msg == "overloaded_error: Overloaded")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_use_round_trip() {
+        let (url, server) = stub::serve(
+            "200 OK",
+            "text/event-stream",
+            vec![concat!(
+                "event: message_start\n",
+                "data: {\"type\":\"message_start\",\"message\":{\"model\":\"claude-3-haiku-20240307\",\"usage\":{\"input_tokens\":40}}}\n\n",
+                "event: content_block_start\n",
+                "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_2\",\"name\":\"calculator\",\"input\":{}}}\n\n",
+                "event: content_block_delta\n",
+                "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"expression\\\": \"}}\n\n",
+                "event: content_block_delta\n",
+                "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"\\\"1+1\\\"}\"}}\n\n",
+                "event: content_block_stop\n",
+                "data: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+                "event: message_delta\n",
+                "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"tool_use\"},\"usage\":{\"output_tokens\":20}}\n\n",
+                "event: message_stop\n",
+                "data: {\"type\":\"message_stop\"}\n\n",
+            )
+            .to_string()],
+        );
+
+        let call = |id: &str| ToolCall {
+            id: Some(id.to_string()),
+            function: FunctionCall {
+                name: "read_file".to_string(),
+                arguments: serde_json::json!({"path": id}),
+            },
+        };
+        let result = |id: &str| Message {
+            role: Role::Tool,
+            content: format!("contents of {}", id),
+            tool_call_id: Some(id.to_string()),
+            ..Default::default()
+        };
+        let mut request = request();
+        request.messages.push(Message {
+            role: Role::Assistant,
+            tool_calls: vec![call("a"), call("b")],
+            ..Default::default()
+        });
+        request.messages.extend([result("a"), result("b")]);
+        request.tools = vec![Tool::function(
+            "calculator",
+            "Evaluates arithmetic.",
+            serde_json::json!({"type": "object"}),
+        )];
+
+        let backend = Anthropic::new(reqwest::Client::new(), &url, None);
+        let (tx, rx) = mpsc::channel();
+        backend
+            .stream(request, &Responder::new(tx, 0))
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["tools"][0]["input_schema"]["type"], "object");
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][0]["type"], "tool_use");
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(messages[2]["content"][1]["tool_use_id"], "b");
+
+        let last = stub::chunks(&rx).pop().unwrap();
+        assert_eq!(last.done_reason.as_deref(), Some("tool_use"));
+        assert_eq!(last.message.tool_calls[0].id.as_deref(), Some("toolu_2"));
+        assert_eq!(
+            last.message.tool_calls[0].function.arguments,
+            serde_json::json!({"expression": "1+1"})
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use super::{
+    BackendError, BoxFuture, ChatBackend, ChatRequest, ChatResponse, Responder, Role, Stats,
+};
+
+/// Streams canned or echoed replies without any server, for demos and tests.
+///
+/// Configured through the model part of the spec, a mode followed by comma separated settings,
+/// e.g. `mock:echo,delay=50,fail=2`:
+/// - `echo` replies with the last user message, `script=<file>` with the turns of a JSONL file.
+/// - `delay=<ms>` between chunks (default 30) and `first=<ms>` before the first one.
+/// - `fail=<n>` fails the first `n` requests with a retryable 503.
+/// - `drop=<n>` cuts every stream off with an error after `n` chunks.
+#[derive(Debug)]
+pub struct Mock {
+    mode: Mode,
+    delay: Duration,
+    first_delay: Duration,
+    fail: u32,
+    drop_after: Option<usize>,
+    /// Requests received so far, failed ones included.
+    served: AtomicU32,
+}
+
+#[derive(Debug)]
+enum Mode {
+    Echo,
+    /// One entry per user turn, wrapping around once the script runs out.
+    Script(Vec<Turn>),
+}
+
+/// A line of a script. The reply is streamed word by word, then the turn fails if it has an
+/// `error`, e.g. `{"reply": "Hello there", "delay": 100}` or `{"error": "model crashed"}`.
+#[derive(Debug, Deserialize)]
+struct Turn {
+    #[serde(default)]
+    reply: String,
+    /// Overrides the mock's `delay` for this turn.
+    #[serde(default)]
+    delay: Option<u64>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+impl Mock {
+    pub const DEFAULT_DELAY: Duration = Duration::from_millis(30);
+
+    pub fn parse(model: &str) -> Result<Self, String> {
+        let mut settings = model.split(',');
+        let mode = match settings.next().unwrap_or_default() {
+            "echo" => Mode::Echo,
+            mode => match mode.strip_prefix("script=") {
+                Some(path) => Mode::Script(Self::load_script(path)?),
+                None => {
+                    return Err(format!(
+                        "Unknown mock `{}`, expected echo or script=<file>",
+                        mode
+                    ))
+                }
+            },
+        };
+
+        let mut mock = Self {
+            mode,
+            delay: Self::DEFAULT_DELAY,
+            first_delay: Duration::ZERO,
+            fail: 0,
+            drop_after: None,
+            served: AtomicU32::new(0),
+        };
+        for setting in settings {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value in mock setting `{}`", setting))?;
+            let value = value
+                .parse::<u64>()
+                .map_err(|_| format!("Mock setting {} must be a number, got {}", key, value))?;
+            match key {
+                "delay" => mock.delay = Duration::from_millis(value),
+                "first" => mock.first_delay = Duration::from_millis(value),
+                "fail" => mock.fail = value as u32,
+                "drop" => mock.drop_after = Some(value as usize),
+                _ => return Err(format!("Unknown mock setting {}", key)),
+            }
+        }
+        Ok(mock)
+    }
+
+    fn load_script(path: &str) -> Result<Vec<Turn>, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read mock script {}: {}", path, e))?;
+        let turns = content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| format!("Bad turn on line {} of {}: {}", i + 1, path, e))
+            })
+            .collect::<Result<Vec<Turn>, _>>()?;
+        if turns.is_empty() {
+            return Err(format!("Mock script {} has no turns", path));
+        }
+        Ok(turns)
+    }
+
+    /// What to answer `request` with, and the error to end on, if any.
+    fn reply<'a>(&'a self, request: &'a ChatRequest) -> (&'a str, Duration, Option<&'a str>) {
+        let mut user = request
+            .messages
+            .iter()
+            .filter(|msg| matches!(msg.role, Role::User));
+        match &self.mode {
+            Mode::Echo => {
+                let last = user.next_back().map_or("", |msg| msg.content.as_str());
+                (last, self.delay, None)
+            }
+            Mode::Script(turns) => {
+                let turn = &turns[user.count().saturating_sub(1) % turns.len()];
+                let delay = turn.delay.map_or(self.delay, Duration::from_millis);
+                (&turn.reply, delay, turn.error.as_deref())
+            }
+        }
+    }
+}
+
+impl ChatBackend for Mock {
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        tx: &'a Responder,
+    ) -> BoxFuture<'a, Result<(), BackendError>> {
+        Box::pin(async move {
+            if self.served.fetch_add(1, Ordering::Relaxed) < self.fail {
+                return Err(BackendError::Status {
+                    status: reqwest::StatusCode::SERVICE_UNAVAILABLE,
+                    body: "injected by mock".to_string(),
+                });
+            }
+
+            let (reply, delay, error) = self.reply(&request);
+            let words = reply.split_inclusive(' ').collect::<Vec<_>>();
+            tokio::time::sleep(self.first_delay).await;
+            for (i, word) in words.iter().enumerate() {
+                if self.drop_after == Some(i) {
+                    return Err(BackendError::Provider("stream dropped by mock".to_string()));
+                }
+                if i > 0 {
+                    tokio::time::sleep(delay).await;
+                }
+                tx.chunk(ChatResponse::new(
+                    request.model.clone(),
+                    word.to_string(),
+                    false,
+                ))?;
+            }
+            if let Some(error) = error {
+                return Err(BackendError::Provider(error.to_string()));
+            }
+
+            let mut last = ChatResponse::new(request.model.clone(), String::new(), true);
+            last.done_reason = Some("stop".to_string());
+            last.stats = Stats {
+                eval_count: Some(words.len() as u64),
+                ..Default::default()
+            };
+            tx.chunk(last)
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        "mock"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::backend::{stub, Message};
+    use std::sync::mpsc;
+
+    fn request(turns: &[&str]) -> ChatRequest {
+        ChatRequest {
+            model: "mock".to_string(),
+            messages: turns
+                .iter()
+                .map(|turn| Message {
+                    role: Role::User,
+                    content: turn.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    async fn replay(mock: &Mock, turns: &[&str]) -> (String, Result<(), BackendError>) {
+        let (tx, rx) = mpsc::channel();
+        let outcome = mock.stream(request(turns), &Responder::new(tx, 0)).await;
+        let content = stub::chunks(&rx)
+            .iter()
+            .map(|c| c.message.content.as_str())
+            .collect();
+        (content, outcome)
+    }
+
+    #[tokio::test]
+    async fn test_script_turns() {
+        let path = std::env::temp_dir().join(format!("mock-script-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            "{\"reply\": \"Hi there\", \"delay\": 0}\n\n{\"reply\": \"Oh no\", \"error\": \"model crashed\"}\n",
+        )
+        .unwrap();
+        let mock = Mock::parse(&format!("script={},delay=0", path.display())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (content, outcome) = replay(&mock, &["a"]).await;
+        assert_eq!(content, "Hi there");
+        assert!(outcome.is_ok());
+
+        let (content, outcome) = replay(&mock, &["a", "b"]).await;
+        assert_eq!(content, "Oh no");
+        assert!(matches!(outcome, Err(BackendError::Provider(e)) if e == "model crashed"));
+
+        // Wraps around.
+        let (content, _) = replay(&mock, &["a", "b", "c"]).await;
+        assert_eq!(content, "Hi there");
+    }
+
+    #[tokio::test]
+    async fn test_failure_injection() {
+        let mock = Mock::parse("echo,delay=0,fail=1,drop=2").unwrap();
+
+        let (_, outcome) = replay(&mock, &["one two three"]).await;
+        assert!(outcome.unwrap_err().is_transient());
+
+        let (content, outcome) = replay(&mock, &["one two three"]).await;
+        assert_eq!(content, "one two ");
+        assert!(matches!(outcome, Err(BackendError::Provider(_))));
+
+        assert!(Mock::parse("parrot").is_err());
+        assert!(Mock::parse("echo,delay=soon").is_err());
+    }
+}
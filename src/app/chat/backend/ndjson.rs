@@ -0,0 +1,145 @@
+use serde::de::DeserializeOwned;
+
+/// Incremental newline-delimited JSON decoder.
+///
+/// HTTP chunk boundaries have nothing to do with object boundaries: a chunk may end in the
+/// middle of an object or carry several of them. Bytes are buffered until a full line is
+/// available, and only complete lines are handed to `serde_json`.
+#[derive(Debug, Default)]
+pub struct NdjsonDecoder {
+    buffer: Vec<u8>,
+}
+
+impl NdjsonDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `bytes` and decodes every line completed by them.
+    pub fn feed<T: DeserializeOwned>(&mut self, bytes: &[u8]) -> Result<Vec<T>, serde_json::Error> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut values = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=pos).collect::<Vec<_>>();
+            if let Some(value) = Self::decode(&line)? {
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Decodes whatever is left once the stream has ended, for servers that do not terminate
+    /// the last object with a newline.
+    pub fn finish<T: DeserializeOwned>(&mut self) -> Result<Option<T>, serde_json::Error> {
+        let rest = std::mem::take(&mut self.buffer);
+        Self::decode(&rest)
+    }
+
+    fn decode<T: DeserializeOwned>(line: &[u8]) -> Result<Option<T>, serde_json::Error> {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return Ok(None);
+        }
+        serde_json::from_slice(line).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Chunk {
+        content: String,
+        done: bool,
+    }
+
+    const STREAM: &str = concat!(
+        "{\"content\":\"Hel\",\"done\":false}\n",
+        "{\"content\":\"lo, wörld \\n\",\"done\":false}\r\n",
+        "\n",
+        "{\"content\":\"\",\"done\":true}",
+    );
+
+    fn expected() -> Vec<Chunk> {
+        vec![
+            Chunk {
+                content: "Hel".to_string(),
+                done: false,
+            },
+            Chunk {
+                content: "lo, wörld \n".to_string(),
+                done: false,
+            },
+            Chunk {
+                content: String::new(),
+                done: true,
+            },
+        ]
+    }
+
+    fn decode_in(parts: &[&[u8]]) -> Vec<Chunk> {
+        let mut decoder = NdjsonDecoder::new();
+        let mut chunks = Vec::new();
+        for part in parts {
+            chunks.extend(decoder.feed::<Chunk>(part).unwrap());
+        }
+        chunks.extend(decoder.finish::<Chunk>().unwrap());
+        chunks
+    }
+
+    #[test]
+    fn test_whole_stream_in_one_chunk() {
+        assert_eq!(decode_in(&[STREAM.as_bytes()]), expected());
+    }
+
+    #[test]
+    fn test_every_two_way_split() {
+        let bytes = STREAM.as_bytes();
+        for split in 0..=bytes.len() {
+            let (head, tail) = bytes.split_at(split);
+            assert_eq!(decode_in(&[head, tail]), expected(), "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn test_byte_by_byte() {
+        let parts = STREAM.as_bytes().chunks(1).collect::<Vec<_>>();
+        assert_eq!(decode_in(&parts), expected());
+    }
+
+    #[test]
+    fn test_uneven_chunks_splitting_multibyte_characters() {
+        let bytes = STREAM.as_bytes();
+        for size in [2, 3, 7, 13, 31] {
+            let parts = bytes.chunks(size).collect::<Vec<_>>();
+            assert_eq!(decode_in(&parts), expected(), "chunk size {}", size);
+        }
+    }
+
+    #[test]
+    fn test_partial_line_is_held_back() {
+        let mut decoder = NdjsonDecoder::new();
+        let chunks = decoder
+            .feed::<Chunk>(b"{\"content\":\"a\",\"done\":false}\n{\"content\":")
+            .unwrap();
+        assert_eq!(chunks.len(), 1);
+
+        let chunks = decoder.feed::<Chunk>(b"\"b\",\"done\":true}\n").unwrap();
+        assert_eq!(
+            chunks,
+            vec![Chunk {
+                content: "b".to_string(),
+                done: true
+            }]
+        );
+        assert_eq!(decoder.finish::<Chunk>().unwrap(), None);
+    }
+
+    #[test]
+    fn test_malformed_line_is_an_error() {
+        let mut decoder = NdjsonDecoder::new();
+        assert!(decoder.feed::<Chunk>(b"{\"content\":\n").is_err());
+    }
+}
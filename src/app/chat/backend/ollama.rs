@@ -0,0 +1,370 @@
+use serde::Deserialize;
+
+use super::ndjson::NdjsonDecoder;
+use super::{
+    check_status, BackendError, BoxFuture, ChatBackend, ChatRequest, ChatResponse, Responder,
+};
+
+/// Talks to an Ollama server through its native `/api/chat` endpoint.
+#[derive(Debug)]
+pub struct Ollama {
+    client: reqwest::Client,
+    base_url: String,
+}
+
+impl Ollama {
+    pub const DEFAULT_PORT: u16 = 11434;
+
+    pub fn new(client: reqwest::Client, base_url: &str) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+/// An entry of `/api/tags`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModelInfo {
+    pub name: String,
+    /// On-disk size in bytes.
+    pub size: u64,
+    #[serde(default)]
+    pub details: ModelDetails,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ModelDetails {
+    #[serde(default)]
+    pub family: String,
+    #[serde(default)]
+    pub parameter_size: String,
+    #[serde(default)]
+    pub quantization_level: String,
+}
+
+#[derive(Deserialize)]
+struct Tags {
+    models: Vec<ModelInfo>,
+}
+
+/// Lists the models available locally on the Ollama server at `base_url`.
+pub async fn list_models(
+    client: &reqwest::Client,
+    base_url: &str,
+) -> Result<Vec<ModelInfo>, BackendError> {
+    let response = client
+        .get(format!("{}/api/tags", base_url.trim_end_matches('/')))
+        .send()
+        .await?;
+    let tags: Tags = check_status(response).await?.json().await?;
+    Ok(tags.models)
+}
+
+#[derive(Deserialize)]
+struct Embeddings {
+    embeddings: Vec<Vec<f32>>,
+}
+
+/// Embeds each of `input` with `model` on the Ollama server at `base_url`, through `/api/embed`.
+pub async fn embed(
+    client: &reqwest::Client,
+    base_url: &str,
+    model: &str,
+    input: &[String],
+) -> Result<Vec<Vec<f32>>, BackendError> {
+    let response = client
+        .post(format!("{}/api/embed", base_url.trim_end_matches('/')))
+        .json(&serde_json::json!({ "model": model, "input": input }))
+        .send()
+        .await?;
+    let embeddings: Embeddings = check_status(response).await?.json().await?;
+    if embeddings.embeddings.len() != input.len() {
+        return Err(BackendError::Provider(format!(
+            "got {} embeddings for {} inputs",
+            embeddings.embeddings.len(),
+            input.len()
+        )));
+    }
+    Ok(embeddings.embeddings)
+}
+
+/// One status line of `/api/pull`. Download steps come with the digest being fetched and its
+/// byte counts; the last line has the status `success`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullProgress {
+    pub status: String,
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub total: Option<u64>,
+    #[serde(default)]
+    pub completed: Option<u64>,
+}
+
+impl PullProgress {
+    pub fn is_success(&self) -> bool {
+        self.status == "success"
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PullLine {
+    Error { error: String },
+    Progress(PullProgress),
+}
+
+/// Pulls `model` onto the Ollama server at `base_url`, calling `on_progress` for every status
+/// line it streams back.
+pub async fn pull(
+    client: &reqwest::Client,
+    base_url: &str,
+    model: &str,
+    on_progress: impl Fn(PullProgress),
+) -> Result<(), BackendError> {
+    let response = client
+        .post(format!("{}/api/pull", base_url.trim_end_matches('/')))
+        // Older servers only know the model as `name`.
+        .json(&serde_json::json!({ "model": model, "name": model, "stream": true }))
+        .send()
+        .await?;
+    let mut response = check_status(response).await?;
+
+    let mut decoder = NdjsonDecoder::new();
+    while let Some(chunk) = response.chunk().await? {
+        for line in decoder.feed::<PullLine>(&chunk)? {
+            match line {
+                PullLine::Error { error } => return Err(BackendError::Provider(error)),
+                PullLine::Progress(progress) => on_progress(progress),
+            }
+        }
+    }
+    match decoder.finish::<PullLine>()? {
+        Some(PullLine::Error { error }) => Err(BackendError::Provider(error)),
+        Some(PullLine::Progress(progress)) => {
+            on_progress(progress);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Ollama reports some failures, e.g. a model crashing mid-generation, as an `{"error": ...}`
+/// line in an otherwise successful stream.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Line {
+    Error { error: String },
+    Chunk(Box<ChatResponse>),
+}
+
+impl Line {
+    fn forward(self, tx: &Responder) -> Result<(), BackendError> {
+        match self {
+            Line::Error { error } => Err(BackendError::Provider(error)),
+            Line::Chunk(chat_response) => tx.chunk(*chat_response),
+        }
+    }
+}
+
+impl ChatBackend for Ollama {
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        tx: &'a Responder,
+    ) -> BoxFuture<'a, Result<(), BackendError>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(format!("{}/api/chat", self.base_url))
+                .json(&request)
+                .send()
+                .await?;
+            let mut response = check_status(response).await?;
+
+            let mut decoder = NdjsonDecoder::new();
+            while let Some(chunk) = response.chunk().await? {
+                for line in decoder.feed::<Line>(&chunk)? {
+                    line.forward(tx)?;
+                }
+            }
+            if let Some(line) = decoder.finish::<Line>()? {
+                line.forward(tx)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.base_url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::backend::{stub, Message, Role};
+    use std::sync::mpsc;
+
+    fn request() -> ChatRequest {
+        ChatRequest {
+            model: "llama3".to_string(),
+            messages: vec![Message {
+                role: Role::User,
+                content: "Hello".to_string(),
+                images: vec!["iVBORw0KGgo".to_string()],
+                ..Default::default()
+            }],
+            options: [("seed".to_string(), serde_json::json!(7))].into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams_from_configured_endpoint() {
+        let (url, server) = stub::serve(
+            "200 OK",
+            "application/x-ndjson",
+            vec![
+                "{\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"Hi\"},\"done\":false}\n{\"model\":".to_string(),
+                "\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done_reason\":\"stop\",\"done\":true,\"total_duration\":4600646509,\"load_duration\":2069650368,\"prompt_eval_count\":10,\"prompt_eval_duration\":326180000,\"eval_count\":26,\"eval_duration\":2072971000}\n".to_string(),
+            ],
+        );
+
+        let (tx, rx) = mpsc::channel();
+        Ollama::new(reqwest::Client::new(), &format!("{}/", url))
+            .stream(request(), &Responder::new(tx, 0))
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["model"], "llama3");
+        assert_eq!(body["options"]["seed"], 7);
+        assert_eq!(body["messages"][0]["images"][0], "iVBORw0KGgo");
+
+        let chunks = stub::chunks(&rx);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].message.content, "Hi");
+        assert!(chunks[1].done);
+        assert_eq!(chunks[1].done_reason.as_deref(), Some("stop"));
+        assert_eq!(chunks[1].stats.eval_count, Some(26));
+        assert_eq!(chunks[1].stats.eval_duration, Some(2072971000));
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        let (url, _server) = stub::serve(
+            "200 OK",
+            "application/json",
+            vec![concat!(
+                "{\"models\":[{\"name\":\"llama3:latest\",\"model\":\"llama3:latest\",",
+                "\"modified_at\":\"2024-06-09T15:50:12.5Z\",\"size\":4661224676,",
+                "\"digest\":\"365c0bd3c000\",\"details\":{\"parent_model\":\"\",\"format\":\"gguf\",",
+                "\"family\":\"llama\",\"families\":[\"llama\"],\"parameter_size\":\"8.0B\",",
+                "\"quantization_level\":\"Q4_0\"}}]}",
+            )
+            .to_string()],
+        );
+
+        let models = list_models(&reqwest::Client::new(), &url).await.unwrap();
+
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "llama3:latest");
+        assert_eq!(models[0].size, 4661224676);
+        assert_eq!(models[0].details.family, "llama");
+        assert_eq!(models[0].details.quantization_level, "Q4_0");
+    }
+
+    #[tokio::test]
+    async fn test_embed() {
+        let (url, server) = stub::serve(
+            "200 OK",
+            "application/json",
+            vec![
+                "{\"model\":\"nomic-embed-text\",\"embeddings\":[[0.1,0.2],[0.3,0.4]]}".to_string(),
+            ],
+        );
+
+        let input = ["first".to_string(), "second".to_string()];
+        let embeddings = embed(&reqwest::Client::new(), &url, "nomic-embed-text", &input)
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["input"], serde_json::json!(["first", "second"]));
+        assert_eq!(embeddings, [[0.1, 0.2], [0.3, 0.4]]);
+    }
+
+    #[tokio::test]
+    async fn test_pull_reports_progress() {
+        let (url, server) = stub::serve(
+            "200 OK",
+            "application/x-ndjson",
+            vec![
+                "{\"status\":\"pulling manifest\"}\n{\"status\":\"pulling 6a0746a1ec1a\",\"digest\":\"sha256:6a07\",\"total\":400,".to_string(),
+                "\"completed\":100}\n{\"status\":\"pulling 6a0746a1ec1a\",\"digest\":\"sha256:6a07\",\"total\":400,\"completed\":400}\n".to_string(),
+                "{\"status\":\"verifying sha256 digest\"}\n{\"status\":\"success\"}\n".to_string(),
+            ],
+        );
+
+        let seen = std::sync::Mutex::new(Vec::new());
+        pull(&reqwest::Client::new(), &url, "phi3", |progress| {
+            seen.lock().unwrap().push(progress)
+        })
+        .await
+        .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["model"], "phi3");
+
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen.len(), 5);
+        assert_eq!(seen[1].completed, Some(100));
+        assert_eq!(seen[1].total, Some(400));
+        assert!(seen[4].is_success());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_model_reports_status_and_body() {
+        let (url, _server) = stub::serve(
+            "404 Not Found",
+            "application/json",
+            vec!["{\"error\":\"model 'lama3' not found, try pulling it first\"}".to_string()],
+        );
+
+        let (tx, _rx) = mpsc::channel();
+        let error = Ollama::new(reqwest::Client::new(), &url)
+            .stream(request(), &Responder::new(tx, 0))
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "HTTP 404 Not Found: {\"error\":\"model 'lama3' not found, try pulling it first\"}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_line_mid_stream() {
+        let (url, _server) = stub::serve(
+            "200 OK",
+            "application/x-ndjson",
+            vec![
+                "{\"model\":\"llama3\",\"message\":{\"role\":\"assistant\",\"content\":\"Hi\"},\"done\":false}\n".to_string(),
+                "{\"error\":\"llama runner process has terminated\"}\n".to_string(),
+            ],
+        );
+
+        let (tx, _rx) = mpsc::channel();
+        let error = Ollama::new(reqwest::Client::new(), &url)
+            .stream(request(), &Responder::new(tx, 0))
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(error, BackendError::Provider(msg) if msg == "llama runner process has terminated")
+        );
+    }
+}
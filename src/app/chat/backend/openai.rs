@@ -0,0 +1,486 @@
+use serde::{Deserialize, Serialize};
+
+use super::sse::SseDecoder;
+use super::{
+    check_status, image_media_type, BackendError, BoxFuture, ChatBackend, ChatRequest,
+    ChatResponse, Message, PartialCall, Responder, Role, Stats, Tool,
+};
+
+/// Talks to any server exposing the OpenAI `/v1/chat/completions` API (vLLM, llama.cpp server,
+/// LM Studio, LocalAI, ...).
+#[derive(Debug)]
+pub struct OpenAi {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl OpenAi {
+    pub const DEFAULT_BASE_URL: &'static str = "http://localhost:8080/v1";
+
+    pub fn new(client: reqwest::Client, base_url: &str, api_key: Option<String>) -> Self {
+        Self {
+            client,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+        }
+    }
+
+    /// `base_url` if given, else `OPENAI_BASE_URL` from the environment or the default.
+    pub fn base_url(base_url: Option<String>) -> String {
+        base_url
+            .or_else(|| std::env::var("OPENAI_BASE_URL").ok())
+            .unwrap_or_else(|| Self::DEFAULT_BASE_URL.to_string())
+    }
+
+    /// Picks up `OPENAI_API_KEY` from the environment.
+    pub fn from_env(client: reqwest::Client, base_url: &str) -> Self {
+        Self::new(client, base_url, std::env::var("OPENAI_API_KEY").ok())
+    }
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<ChatMessage<'a>>,
+    stream: bool,
+    stream_options: StreamOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<&'a serde_json::Value>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [Tool],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+}
+
+/// Translates Ollama's `format` into OpenAI's `response_format`.
+fn response_format(format: &serde_json::Value) -> serde_json::Value {
+    match format {
+        serde_json::Value::String(_) => serde_json::json!({ "type": "json_object" }),
+        schema => serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema },
+        }),
+    }
+}
+
+#[derive(Serialize)]
+struct ChatMessage<'a> {
+    role: &'a Role,
+    content: Content<'a>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OutgoingCall<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+}
+
+/// A call as OpenAI wants it echoed back, with the arguments as a JSON encoded string.
+#[derive(Serialize)]
+struct OutgoingCall<'a> {
+    id: &'a str,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    function: OutgoingFunction<'a>,
+}
+
+#[derive(Serialize)]
+struct OutgoingFunction<'a> {
+    name: &'a str,
+    arguments: String,
+}
+
+/// Plain text, unless there are images, which only fit in a list of content parts.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Content<'a> {
+    Text(&'a str),
+    Parts(Vec<Part<'a>>),
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Part<'a> {
+    Text { text: &'a str },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl<'a> From<&'a Message> for ChatMessage<'a> {
+    fn from(message: &'a Message) -> Self {
+        let content = if message.images.is_empty() {
+            Content::Text(&message.content)
+        } else {
+            let images = message.images.iter().map(|image| Part::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:{};base64,{}", image_media_type(image), image),
+                },
+            });
+            Content::Parts(
+                std::iter::once(Part::Text {
+                    text: &message.content,
+                })
+                .chain(images)
+                .collect(),
+            )
+        };
+
+        let tool_calls = message
+            .tool_calls
+            .iter()
+            .map(|call| OutgoingCall {
+                id: call.id.as_deref().unwrap_or_default(),
+                kind: "function",
+                function: OutgoingFunction {
+                    name: &call.function.name,
+                    arguments: call.function.arguments.to_string(),
+                },
+            })
+            .collect();
+
+        Self {
+            role: &message.role,
+            content,
+            tool_calls,
+            tool_call_id: message.tool_call_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StreamOptions {
+    /// Asks for a last chunk carrying token usage before `[DONE]`.
+    include_usage: bool,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunk {
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    choices: Vec<Choice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    #[serde(default)]
+    delta: Delta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    #[serde(default)]
+    completion_tokens_details: Option<CompletionDetails>,
+}
+
+#[derive(Deserialize)]
+struct CompletionDetails {
+    #[serde(default)]
+    reasoning_tokens: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+    /// Sent by DeepSeek, vLLM and others serving reasoning models, under either name.
+    #[serde(default, alias = "reasoning")]
+    reasoning_content: Option<String>,
+    #[serde(default)]
+    tool_calls: Vec<CallDelta>,
+}
+
+/// A piece of a tool call; the name comes first, the arguments trickle in as string fragments.
+#[derive(Deserialize)]
+struct CallDelta {
+    #[serde(default)]
+    index: usize,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    function: FunctionDelta,
+}
+
+#[derive(Default, Deserialize)]
+struct FunctionDelta {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    arguments: Option<String>,
+}
+
+impl ChatBackend for OpenAi {
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        tx: &'a Responder,
+    ) -> BoxFuture<'a, Result<(), BackendError>> {
+        Box::pin(async move {
+            let body = CompletionRequest {
+                model: &request.model,
+                messages: request.messages.iter().map(ChatMessage::from).collect(),
+                stream: true,
+                stream_options: StreamOptions {
+                    include_usage: true,
+                },
+                // There is no equivalent to `num_ctx`, the server decides on the context size.
+                temperature: request.options.get("temperature"),
+                top_p: request.options.get("top_p"),
+                seed: request.options.get("seed"),
+                max_tokens: request.options.get("num_predict"),
+                stop: request.options.get("stop"),
+                tools: &request.tools,
+                response_format: request.format.as_ref().map(response_format),
+            };
+
+            let mut builder = self
+                .client
+                .post(format!("{}/chat/completions", self.base_url))
+                .json(&body);
+            if let Some(key) = &self.api_key {
+                builder = builder.bearer_auth(key);
+            }
+            let mut response = check_status(builder.send().await?).await?;
+
+            let mut model = request.model.clone();
+            let mut stats = Stats::default();
+            let mut done_reason = None;
+            let mut calls: Vec<PartialCall> = Vec::new();
+
+            // Returns whether the `[DONE]` sentinel has been reached.
+            let mut handle = |data: &str| -> Result<bool, BackendError> {
+                if data == "[DONE]" {
+                    return Ok(true);
+                }
+
+                let chunk: CompletionChunk = serde_json::from_str(data)?;
+                if let Some(chunk_model) = chunk.model {
+                    model = chunk_model;
+                }
+                if let Some(usage) = chunk.usage {
+                    stats.prompt_eval_count = Some(usage.prompt_tokens);
+                    stats.eval_count = Some(usage.completion_tokens);
+                    stats.thinking_count = usage
+                        .completion_tokens_details
+                        .and_then(|details| details.reasoning_tokens);
+                }
+
+                let mut content = String::new();
+                let mut thinking = String::new();
+                for choice in chunk.choices {
+                    content += &choice.delta.content.unwrap_or_default();
+                    thinking += &choice.delta.reasoning_content.unwrap_or_default();
+                    for delta in choice.delta.tool_calls {
+                        if calls.len() <= delta.index {
+                            calls.resize_with(delta.index + 1, PartialCall::default);
+                        }
+                        let call = &mut calls[delta.index];
+                        call.id = delta.id.or(call.id.take());
+                        call.name += &delta.function.name.unwrap_or_default();
+                        call.arguments += &delta.function.arguments.unwrap_or_default();
+                    }
+                    done_reason = choice.finish_reason.or(done_reason.take());
+                }
+                if !content.is_empty() || !thinking.is_empty() {
+                    let mut chunk = ChatResponse::new(model.clone(), content, false);
+                    chunk.message.thinking = thinking;
+                    tx.chunk(chunk)?;
+                }
+                Ok(false)
+            };
+
+            let mut decoder = SseDecoder::new();
+            'stream: while let Some(chunk) = response.chunk().await? {
+                for event in decoder.feed(&chunk) {
+                    if handle(&event.data)? {
+                        break 'stream;
+                    }
+                }
+            }
+            // Some servers just close the connection instead of sending the sentinel.
+            if let Some(event) = decoder.finish() {
+                handle(&event.data)?;
+            }
+
+            let mut last = ChatResponse::new(model, String::new(), true);
+            last.message.tool_calls = calls
+                .into_iter()
+                .map(PartialCall::finish)
+                .collect::<Result<_, _>>()?;
+            last.done_reason = done_reason;
+            last.stats = stats;
+            tx.chunk(last)
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        &self.base_url
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::backend::{stub, FunctionCall, ToolCall};
+    use std::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_streams_completion_chunks() {
+        let (url, server) = stub::serve(
+            "200 OK",
+            "text/event-stream",
+            vec![
+                "data: {\"model\":\"qwen\",\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n"
+                    .to_string(),
+                "data: {\"model\":\"qwen\",\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"model\":\"qwen\",\"choi".to_string(),
+                "ces\":[{\"delta\":{\"content\":\"lo\"},\"finish_reason\":\"stop\"}]}\n\n".to_string(),
+                "data: {\"model\":\"qwen\",\"choices\":[],\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":2,\"total_tokens\":11}}\n\n".to_string(),
+                "data: [DONE]\n\n".to_string(),
+            ],
+        );
+
+        let backend = OpenAi::new(reqwest::Client::new(), &format!("{}/v1/", url), None);
+        let (tx, rx) = mpsc::channel();
+        let request = ChatRequest {
+            model: "qwen".to_string(),
+            messages: vec![Message {
+                role: Role::User,
+                content: "Hi".to_string(),
+                images: vec!["/9j/4AAQ".to_string()],
+                ..Default::default()
+            }],
+            options: [
+                ("temperature".to_string(), serde_json::json!(0.2)),
+                ("num_predict".to_string(), serde_json::json!(64)),
+                ("num_ctx".to_string(), serde_json::json!(4096)),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        backend
+            .stream(request, &Responder::new(tx, 0))
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["messages"][0]["role"], "user");
+        assert_eq!(
+            body["messages"][0]["content"],
+            serde_json::json!([
+                {"type": "text", "text": "Hi"},
+                {"type": "image_url", "image_url": {"url": "data:image/jpeg;base64,/9j/4AAQ"}},
+            ])
+        );
+        assert_eq!(body["temperature"], 0.2);
+        assert_eq!(body["max_tokens"], 64);
+        assert!(body.get("num_ctx").is_none());
+        assert!(body.get("options").is_none());
+
+        let chunks = stub::chunks(&rx);
+        let content = chunks
+            .iter()
+            .map(|c| c.message.content.as_str())
+            .collect::<String>();
+        assert_eq!(content, "Hello");
+        assert_eq!(chunks.iter().filter(|c| c.done).count(), 1);
+
+        let last = chunks.last().unwrap();
+        assert!(last.done);
+        assert_eq!(last.done_reason.as_deref(), Some("stop"));
+        assert_eq!(last.stats.prompt_eval_count, Some(9));
+        assert_eq!(last.stats.eval_count, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_assembles_streamed_tool_calls() {
+        let (url, server) = stub::serve(
+            "200 OK",
+            "text/event-stream",
+            vec![
+                "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"type\":\"function\",\"function\":{\"name\":\"calculator\",\"arguments\":\"\"}}]}}]}\n\n".to_string(),
+                "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"expression\\\":\"}}]}}]}\n\n".to_string(),
+                "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"2*21\\\"}\"}}]},\"finish_reason\":\"tool_calls\"}]}\n\n".to_string(),
+                "data: [DONE]\n\n".to_string(),
+            ],
+        );
+
+        let backend = OpenAi::new(reqwest::Client::new(), &url, None);
+        let (tx, rx) = mpsc::channel();
+        let request = ChatRequest {
+            model: "qwen".to_string(),
+            messages: vec![
+                Message {
+                    role: Role::Assistant,
+                    tool_calls: vec![ToolCall {
+                        id: Some("call_0".to_string()),
+                        function: FunctionCall {
+                            name: "list_dir".to_string(),
+                            arguments: serde_json::json!({"path": "."}),
+                        },
+                    }],
+                    ..Default::default()
+                },
+                Message {
+                    role: Role::Tool,
+                    content: "Cargo.toml".to_string(),
+                    tool_call_id: Some("call_0".to_string()),
+                    ..Default::default()
+                },
+            ],
+            tools: vec![Tool::function(
+                "calculator",
+                "Evaluates arithmetic.",
+                serde_json::json!({"type": "object"}),
+            )],
+            format: Some(serde_json::json!("json")),
+            ..Default::default()
+        };
+
+        backend
+            .stream(request, &Responder::new(tx, 0))
+            .await
+            .unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&server.join().unwrap()).unwrap();
+        assert_eq!(body["tools"][0]["function"]["name"], "calculator");
+        assert_eq!(body["response_format"]["type"], "json_object");
+        assert_eq!(
+            body["messages"][0]["tool_calls"][0]["function"]["arguments"],
+            "{\"path\":\".\"}"
+        );
+        assert_eq!(body["messages"][1]["role"], "tool");
+        assert_eq!(body["messages"][1]["tool_call_id"], "call_0");
+
+        let last = stub::chunks(&rx).pop().unwrap();
+        assert_eq!(last.done_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(
+            last.message.tool_calls,
+            [ToolCall {
+                id: Some("call_1".to_string()),
+                function: FunctionCall {
+                    name: "calculator".to_string(),
+                    arguments: serde_json::json!({"expression": "2*21"}),
+                },
+            }]
+        );
+    }
+}
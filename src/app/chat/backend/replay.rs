@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{BackendError, BoxFuture, ChatBackend, ChatRequest, ChatResponse, Responder};
+
+/// One line of a fixture: a chunk, or the error the exchange ended on, and when it happened.
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    /// Milliseconds since the request was sent.
+    at_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunk: Option<ChatResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The chunks of one exchange as they went by, see [`Responder`].
+#[derive(Debug)]
+pub struct Tape {
+    started: Instant,
+    frames: Vec<Frame>,
+}
+
+impl Tape {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, chunk: &ChatResponse) {
+        self.frames.push(Frame {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            chunk: Some(chunk.clone()),
+            error: None,
+        });
+    }
+
+    /// The frames as fixture lines.
+    fn lines(&self) -> serde_json::Result<String> {
+        self.frames
+            .iter()
+            .map(|frame| serde_json::to_string(frame).map(|line| line + "\n"))
+            .collect()
+    }
+
+    fn fail(&mut self, error: &BackendError) {
+        self.frames.push(Frame {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            chunk: None,
+            error: Some(error.to_string()),
+        });
+    }
+}
+
+/// Where the exchange for `request` is kept: named after the model and an FNV-1a hash of the
+/// messages, so the same conversation maps to the same file on every machine.
+fn fixture_path(dir: &Path, request: &ChatRequest) -> PathBuf {
+    let messages = serde_json::to_vec(&request.messages).unwrap_or_default();
+    let hash = messages.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    let model = request
+        .model
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect::<String>();
+    dir.join(format!("{}-{:016x}.jsonl", model, hash))
+}
+
+/// Passes requests on to `inner`, and writes each exchange to a fixture for [`Replay`].
+#[derive(Debug)]
+pub struct Recorder {
+    inner: Arc<dyn ChatBackend>,
+    dir: PathBuf,
+}
+
+impl Recorder {
+    pub fn new(inner: Arc<dyn ChatBackend>, dir: &str) -> Self {
+        Self {
+            inner,
+            dir: PathBuf::from(dir),
+        }
+    }
+
+    async fn save(
+        &self,
+        request: &ChatRequest,
+        lines: serde_json::Result<String>,
+    ) -> std::io::Result<()> {
+        let lines = lines?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(fixture_path(&self.dir, request), lines).await
+    }
+}
+
+impl ChatBackend for Recorder {
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        tx: &'a Responder,
+    ) -> BoxFuture<'a, Result<(), BackendError>> {
+        Box::pin(async move {
+            let tape = Arc::new(Mutex::new(Tape::new()));
+            let outcome = self
+                .inner
+                .stream(request.clone(), &tx.recording(tape.clone()))
+                .await;
+
+            // Whatever the chat is no longer listening to isn't worth keeping.
+            if !matches!(outcome, Err(BackendError::Disconnected)) {
+                let lines = {
+                    let mut tape = tape.lock().unwrap();
+                    if let Err(e) = &outcome {
+                        tape.fail(e);
+                    }
+                    tape.lines()
+                };
+                // A retry of the same request overwrites this one, the last attempt wins.
+                if let Err(e) = self.save(&request, lines).await {
+                    super::warn!("Failed to record exchange for {}: {}", request.model, e);
+                }
+            }
+            outcome
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        self.inner.endpoint()
+    }
+}
+
+/// Serves recorded exchanges with their original timing, without talking to any server.
+#[derive(Debug)]
+pub struct Replay {
+    dir: PathBuf,
+}
+
+impl Replay {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+impl ChatBackend for Replay {
+    fn stream<'a>(
+        &'a self,
+        request: ChatRequest,
+        tx: &'a Responder,
+    ) -> BoxFuture<'a, Result<(), BackendError>> {
+        Box::pin(async move {
+            let path = fixture_path(&self.dir, &request);
+            let content = tokio::fs::read_to_string(&path).await.map_err(|e| {
+                BackendError::Provider(format!("no recording at {}: {}", path.display(), e))
+            })?;
+
+            // The clock starts once the fixture is in, reading it isn't part of the exchange.
+            let started = Instant::now();
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                let frame: Frame = serde_json::from_str(line)?;
+                let at = Duration::from_millis(frame.at_ms);
+                tokio::time::sleep(at.saturating_sub(started.elapsed())).await;
+                match (frame.chunk, frame.error) {
+                    (Some(chunk), _) => tx.chunk(chunk)?,
+                    (None, Some(error)) => return Err(BackendError::Provider(error)),
+                    (None, None) => {}
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        "replay"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::backend::{mock::Mock, stub, Message, Role};
+    use std::sync::mpsc;
+
+    fn request(content: &str) -> ChatRequest {
+        ChatRequest {
+            model: "llama3:8b".to_string(),
+            messages: vec![Message {
+                role: Role::User,
+                content: content.to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Chunk contents and their offset from the first one.
+    async fn play(backend: &dyn ChatBackend, request: ChatRequest) -> Vec<(String, u128)> {
+        let (tx, rx) = mpsc::channel();
+        backend
+            .stream(request, &Responder::new(tx, 0))
+            .await
+            .unwrap();
+
+        let chunks = stub::chunks(&rx);
+        let first = chunks[0].received_at.unwrap();
+        chunks
+            .iter()
+            .map(|c| {
+                let offset = c.received_at.unwrap() - first;
+                (c.message.content.clone(), offset.as_millis())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_replays_recorded_exchange() {
+        let dir = std::env::temp_dir().join(format!("fixtures-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let recorder = Recorder::new(Arc::new(Mock::parse("echo,delay=40").unwrap()), dir);
+        let replay = Replay::new(dir);
+
+        let recorded = play(&recorder, request("one two three")).await;
+        let replayed = play(&replay, request("one two three")).await;
+        let missing = replay
+            .stream(request("four"), &Responder::new(mpsc::channel().0, 0))
+            .await;
+        std::fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(recorded.len(), replayed.len());
+        for ((content, at), (replayed_content, replayed_at)) in recorded.iter().zip(&replayed) {
+            assert_eq!(content, replayed_content);
+            assert!(at.abs_diff(*replayed_at) < 20, "{} vs {}", at, replayed_at);
+        }
+        assert!(replayed[2].1 >= 80);
+        assert!(matches!(missing, Err(BackendError::Provider(_))));
+    }
+}
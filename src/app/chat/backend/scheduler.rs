@@ -0,0 +1,248 @@
+use std::collections::{HashMap, VecDeque};
+use tokio::task::JoinHandle;
+
+use super::{handle_streaming_request, BoxFuture, Dispatch, Job, RetryPolicy};
+use crate::app::chat::ChatId;
+
+/// What waits in line for an endpoint.
+enum Queued {
+    Request(Dispatch),
+    Job(BoxFuture<'static, ()>),
+}
+
+/// Runs dispatched requests, and jobs against an endpoint, on the runtime, at most `limit` at a
+/// time against any one endpoint. The others wait in line in the order they came in, and their
+/// chats are told where they stand.
+pub struct Scheduler {
+    policy: RetryPolicy,
+    /// Requests allowed to run at once per endpoint, `0` for no limit.
+    limit: usize,
+    running: HashMap<ChatId, (String, JoinHandle<()>)>,
+    /// Jobs holding a place at their endpoint.
+    jobs: Vec<(String, JoinHandle<()>)>,
+    queues: HashMap<String, VecDeque<Queued>>,
+    /// Whether queue positions moved since the chats were last told.
+    moved: bool,
+}
+
+impl Scheduler {
+    pub fn new(policy: RetryPolicy, limit: usize) -> Self {
+        Self {
+            policy,
+            limit,
+            running: HashMap::new(),
+            jobs: Vec::new(),
+            queues: HashMap::new(),
+            moved: false,
+        }
+    }
+
+    /// Queues the request, replacing whatever the chat had running or waiting: only the latest
+    /// turn is listened to.
+    pub fn submit(&mut self, dispatch: Dispatch) {
+        self.stop(dispatch.chat);
+        let endpoint = dispatch.backend.endpoint().to_string();
+        self.queues
+            .entry(endpoint)
+            .or_default()
+            .push_back(Queued::Request(dispatch));
+    }
+
+    /// Runs a local job right away, and queues one against an endpoint along with its requests.
+    pub fn spawn(&mut self, job: Job) {
+        match job.endpoint {
+            Some(endpoint) => {
+                let queue = self.queues.entry(endpoint).or_default();
+                queue.push_back(Queued::Job(job.future));
+                self.moved = true;
+            }
+            None => {
+                tokio::task::spawn(job.future);
+            }
+        }
+    }
+
+    /// Aborts the request of `chat`, or takes it out of its queue.
+    pub fn stop(&mut self, chat: ChatId) {
+        if let Some((_, handle)) = self.running.remove(&chat) {
+            handle.abort();
+        }
+        for queue in self.queues.values_mut() {
+            queue.retain(
+                |queued| !matches!(queued, Queued::Request(dispatch) if dispatch.chat == chat),
+            );
+        }
+        self.moved = true;
+    }
+
+    /// Forgets finished work and starts queued work wherever that made room. Has to be called
+    /// from within the runtime.
+    pub fn poll(&mut self) {
+        let Self {
+            policy,
+            limit,
+            running,
+            jobs,
+            queues,
+            moved,
+        } = self;
+
+        running.retain(|_, (_, handle)| !handle.is_finished());
+        jobs.retain(|(_, handle)| !handle.is_finished());
+
+        for (endpoint, queue) in queues.iter_mut() {
+            let mut busy = running
+                .values()
+                .chain(jobs.iter())
+                .filter(|(e, _)| e == endpoint)
+                .count();
+            while *limit == 0 || busy < *limit {
+                match queue.pop_front() {
+                    Some(Queued::Request(dispatch)) => {
+                        dispatch.tx.dispatched();
+                        let chat = dispatch.chat;
+                        let request = handle_streaming_request(dispatch, policy.clone());
+                        let handle = tokio::task::spawn(request);
+                        running.insert(chat, (endpoint.clone(), handle));
+                    }
+                    Some(Queued::Job(future)) => {
+                        jobs.push((endpoint.clone(), tokio::task::spawn(future)));
+                    }
+                    None => break,
+                }
+                busy += 1;
+                *moved = true;
+            }
+        }
+
+        if std::mem::take(moved) {
+            for queue in queues.values() {
+                for (index, queued) in queue.iter().enumerate() {
+                    if let Queued::Request(dispatch) = queued {
+                        dispatch.tx.queued(index + 1);
+                    }
+                }
+            }
+        }
+        queues.retain(|_, queue| !queue.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::backend::{
+        BackendError, BoxFuture, ChatBackend, ChatRequest, ChatResponse, Responder, Update,
+    };
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    /// Answers after a while, long enough to keep its endpoint busy.
+    #[derive(Debug)]
+    struct Slow(&'static str);
+
+    impl ChatBackend for Slow {
+        fn stream<'a>(
+            &'a self,
+            _request: ChatRequest,
+            tx: &'a Responder,
+        ) -> BoxFuture<'a, Result<(), BackendError>> {
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                tx.chunk(ChatResponse::new("slow".to_string(), String::new(), true))
+            })
+        }
+
+        fn endpoint(&self) -> &str {
+            self.0
+        }
+    }
+
+    fn dispatch(chat: ChatId, endpoint: &'static str) -> (Dispatch, mpsc::Receiver<Update>) {
+        let (tx, rx) = mpsc::channel();
+        let dispatch = Dispatch {
+            chat,
+            backend: Arc::new(Slow(endpoint)),
+            tx: Responder::new(tx, 1),
+            request: ChatRequest::default(),
+        };
+        (dispatch, rx)
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            first_token_timeout: Duration::from_secs(5),
+            stall_timeout: Duration::from_secs(5),
+            retries: 0,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limits_each_endpoint() {
+        let mut scheduler = Scheduler::new(policy(), 1);
+        let (first, first_rx) = dispatch(0, "http://a");
+        let (second, second_rx) = dispatch(1, "http://a");
+        let (third, third_rx) = dispatch(2, "http://a");
+        let (other, other_rx) = dispatch(3, "http://b");
+        for dispatch in [first, second, third, other] {
+            scheduler.submit(dispatch);
+        }
+        scheduler.poll();
+
+        assert!(matches!(first_rx.try_recv(), Ok(Update::Dispatched { .. })));
+        assert!(matches!(other_rx.try_recv(), Ok(Update::Dispatched { .. })));
+        assert!(matches!(
+            second_rx.try_recv(),
+            Ok(Update::Queued { position: 1, .. })
+        ));
+        assert!(matches!(
+            third_rx.try_recv(),
+            Ok(Update::Queued { position: 2, .. })
+        ));
+
+        // Giving up its place moves everyone behind up.
+        scheduler.stop(1);
+        scheduler.poll();
+        assert!(matches!(
+            third_rx.try_recv(),
+            Ok(Update::Queued { position: 1, .. })
+        ));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(first_rx.try_recv(), Ok(Update::Chunk(_))));
+        scheduler.poll();
+        assert!(matches!(third_rx.try_recv(), Ok(Update::Dispatched { .. })));
+        assert!(second_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jobs_wait_their_turn() {
+        let mut scheduler = Scheduler::new(policy(), 1);
+        let (first, first_rx) = dispatch(0, "http://a");
+        let (again, again_rx) = dispatch(0, "http://a");
+        let (done_tx, done) = mpsc::channel();
+        scheduler.submit(first);
+        scheduler.poll();
+        assert!(matches!(first_rx.try_recv(), Ok(Update::Dispatched { .. })));
+
+        // A job against a busy endpoint waits its turn, and the next turn of a chat replaces the
+        // one still running.
+        scheduler.spawn(Job::on("http://a".to_string(), async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let _ = done_tx.send(());
+        }));
+        scheduler.submit(again);
+        scheduler.poll();
+        assert!(matches!(
+            again_rx.try_recv(),
+            Ok(Update::Queued { position: 1, .. })
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(first_rx.try_recv().is_err());
+        assert!(done.try_recv().is_ok());
+
+        scheduler.poll();
+        assert!(matches!(again_rx.try_recv(), Ok(Update::Dispatched { .. })));
+    }
+}
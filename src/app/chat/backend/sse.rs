@@ -0,0 +1,110 @@
+/// A single server-sent event, as delimited by a blank line on the wire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Incremental `text/event-stream` decoder.
+///
+/// Bytes can be fed in arbitrary slices; an event is only yielded once the blank line
+/// terminating it has been seen, so events split across network chunks are reassembled.
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line = self.buffer.drain(..=pos).collect::<Vec<_>>();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if let Some(event) = self.process_line(line) {
+                events.push(event);
+            }
+        }
+        events
+    }
+
+    /// Flushes whatever is left once the stream has ended, in case the server omitted the
+    /// final blank line.
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let rest = std::mem::take(&mut self.buffer);
+        let rest = String::from_utf8_lossy(&rest);
+        let rest = rest.trim_end_matches(['\n', '\r']);
+        if !rest.is_empty() {
+            self.process_line(rest);
+        }
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => self.data.push(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if self.data.is_empty() {
+            return None;
+        }
+        let data = std::mem::take(&mut self.data).join("\n");
+        Some(SseEvent { event, data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_split_across_chunks() {
+        let stream = b"event: ping\ndata: {\"a\":\r\ndata: 1}\n\n: comment\ndata: [DONE]\n\n";
+
+        for split in 0..stream.len() {
+            let mut decoder = SseDecoder::new();
+            let mut events = decoder.feed(&stream[..split]);
+            events.extend(decoder.feed(&stream[split..]));
+            events.extend(decoder.finish());
+
+            assert_eq!(
+                events,
+                vec![
+                    SseEvent {
+                        event: Some("ping".to_string()),
+                        data: "{\"a\":\n1}".to_string(),
+                    },
+                    SseEvent {
+                        event: None,
+                        data: "[DONE]".to_string(),
+                    },
+                ]
+            );
+        }
+    }
+}
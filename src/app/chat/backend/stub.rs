@@ -0,0 +1,83 @@
+//! A throwaway HTTP server for exercising backends without a real provider.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use super::{ChatResponse, Update};
+
+/// Serves exactly one request with `status` and `content_type`, writing each of `parts` as a
+/// separately flushed write so the client observes them as distinct chunks.
+///
+/// Returns the base URL to point the backend at, and a handle yielding the request body.
+pub fn serve(status: &str, content_type: &str, parts: Vec<String>) -> (String, JoinHandle<String>) {
+    let (url, handle) = serve_each(vec![(status, content_type, parts)]);
+    let handle = std::thread::spawn(move || handle.join().unwrap().pop().unwrap());
+    (url, handle)
+}
+
+/// Like [`serve`], but answers consecutive requests with consecutive replies.
+pub fn serve_each(replies: Vec<(&str, &str, Vec<String>)>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let replies = replies
+        .into_iter()
+        .map(|(status, content_type, parts)| (status.to_string(), content_type.to_string(), parts))
+        .collect::<Vec<_>>();
+
+    let handle = std::thread::spawn(move || {
+        replies
+            .into_iter()
+            .map(|(status, content_type, parts)| reply(&listener, &status, &content_type, parts))
+            .collect()
+    });
+
+    (url, handle)
+}
+
+fn reply(listener: &TcpListener, status: &str, content_type: &str, parts: Vec<String>) -> String {
+    let (stream, _) = listener.accept().unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" || line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\ncontent-type: {}\r\nconnection: close\r\n\r\n",
+        status, content_type
+    )
+    .unwrap();
+    for part in parts {
+        stream.write_all(part.as_bytes()).unwrap();
+        stream.flush().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+    }
+
+    String::from_utf8(body).unwrap()
+}
+
+/// Drains everything a backend sent, failing the test on anything but chunks.
+pub fn chunks(rx: &mpsc::Receiver<Update>) -> Vec<ChatResponse> {
+    rx.try_iter()
+        .map(|update| match update {
+            Update::Chunk(chunk) => chunk,
+            update => panic!("unexpected update: {:?}", update),
+        })
+        .collect()
+}
@@ -0,0 +1,271 @@
+use std::sync::Arc;
+
+use super::backend::{self, ChatBackend, ChatRequest, RequestId};
+use super::tokens::{self, Tokenizer};
+use super::{Author, Message};
+use crate::config::Config;
+
+/// How much of the conversation goes out with each request, set with `/context`.
+pub enum Strategy {
+    /// Everything, leaving it to the server to cut what doesn't fit.
+    Full,
+    /// As many of the latest turns as fit in `budget` tokens.
+    Window { budget: u64 },
+    /// The last `turns` turns.
+    Last { turns: usize },
+    /// The last `keep` turns, with everything before them boiled down to a summary written by
+    /// `summarizer`, or by the chat's own model.
+    Summarize {
+        keep: usize,
+        summarizer: Option<(String, Arc<dyn ChatBackend>)>,
+    },
+}
+
+/// The part of a conversation that left the context, in a few paragraphs.
+pub struct Summary {
+    /// How many of the non-system messages it stands in for.
+    pub covers: usize,
+    pub text: String,
+    /// Token count of `text`, which goes out with every request.
+    pub tokens: u64,
+}
+
+impl Strategy {
+    /// Parses the arguments of `/context` following the chat name.
+    pub fn parse(args: &[String], config: &Config) -> Result<Self, String> {
+        let number = |value: &str| {
+            value
+                .parse::<usize>()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("Expected a positive number, got {}", value))
+        };
+
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        match args.as_slice() {
+            ["full"] => Ok(Self::Full),
+            ["window", budget] => Ok(Self::Window {
+                budget: number(budget)? as u64,
+            }),
+            ["last", turns] => Ok(Self::Last {
+                turns: number(turns)?,
+            }),
+            ["summarize", keep, summarizer @ ..] => Ok(Self::Summarize {
+                keep: number(keep)?,
+                summarizer: match summarizer {
+                    [] => None,
+                    [spec] => Some(backend::resolve(spec, config)?),
+                    _ => return Err("Expected a single model to summarize with".to_string()),
+                },
+            }),
+            _ => Err(
+                "Usage: /context <chat> full|window <tokens>|last <turns>|summarize <turns> [model]"
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// Shown in the pane title, `None` for [`Strategy::Full`].
+    pub fn name(&self) -> Option<String> {
+        match self {
+            Self::Full => None,
+            Self::Window { budget } => Some(format!("window {}", budget)),
+            Self::Last { turns } => Some(format!("last {}", turns)),
+            Self::Summarize {
+                keep,
+                summarizer: None,
+            } => Some(format!("summarize {}", keep)),
+            Self::Summarize {
+                keep,
+                summarizer: Some((model, _)),
+            } => Some(format!("summarize {} by {}", keep, model)),
+        }
+    }
+
+    /// Index of the first message that goes out as is; system messages before it go out too.
+    /// Cuts only ever fall on user messages, so tool results stay with their calls.
+    pub fn start(&self, messages: &[Message], tokenizer: &Tokenizer) -> usize {
+        let turns = messages
+            .iter()
+            .enumerate()
+            .filter(|(_, msg)| msg.author == Author::User)
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let last = |n: usize| match turns.len().checked_sub(n) {
+            Some(first) if first > 0 => turns[first],
+            _ => 0,
+        };
+
+        match self {
+            Self::Full => 0,
+            Self::Last { turns } | Self::Summarize { keep: turns, .. } => last(*turns),
+            Self::Window { budget } => {
+                let mut system = 0;
+                // What the messages from each index on cost, system prompts aside.
+                let mut rest = vec![0; messages.len() + 1];
+                for (index, msg) in messages.iter().enumerate().rev() {
+                    let tokens = tokenizer.count_message(msg);
+                    rest[index] = rest[index + 1];
+                    match msg.author {
+                        Author::System => system += tokens,
+                        _ => rest[index] += tokens,
+                    }
+                }
+                let cost = |start: usize| system + rest[start];
+                // The latest turn goes out whatever it costs, the server gets to complain.
+                let latest = turns.last().copied().unwrap_or(0);
+                std::iter::once(0)
+                    .chain(turns.iter().copied())
+                    .find(|start| cost(*start) <= *budget)
+                    .unwrap_or(latest)
+            }
+        }
+    }
+}
+
+/// How full the context is, e.g. `ctx 1.2k/4k ▰▰▰▱▱`, or just the count without a `size`.
+/// Counts that aren't `exact` get a `~`.
+pub fn meter(used: u64, size: Option<u64>, exact: bool) -> String {
+    const CELLS: u64 = 5;
+    let approx = if exact { "" } else { "~" };
+    let Some(size) = size.filter(|size| *size > 0) else {
+        return format!("ctx {}{}", approx, tokens::compact(used));
+    };
+    let filled = (used * CELLS).div_ceil(size).min(CELLS) as usize;
+    let overflow = if used > size { "!" } else { "" };
+    format!(
+        "ctx {}{}/{} {}{}{}",
+        approx,
+        tokens::compact(used),
+        tokens::compact(size),
+        "▰".repeat(filled),
+        "▱".repeat(CELLS as usize - filled),
+        overflow
+    )
+}
+
+/// The non-system messages before `start`, which a summary has to cover.
+pub fn folded(messages: &[Message], start: usize) -> impl Iterator<Item = &Message> + Clone {
+    messages[..start]
+        .iter()
+        .filter(|msg| msg.author != Author::System)
+}
+
+/// The request asking `model` to fold `messages` into `previous`. It goes out like any other,
+/// through the scheduler, with retries and the watchdog.
+pub fn request<'a>(
+    model: &str,
+    previous: Option<&Summary>,
+    messages: impl Iterator<Item = &'a Message>,
+    id: RequestId,
+) -> ChatRequest {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript += &format!("Summary of what came before:\n{}\n\n", previous.text);
+    }
+    for msg in messages {
+        let author = match msg.author {
+            Author::System => "System",
+            Author::User => "User",
+            Author::Bot => "Assistant",
+            Author::Tool => "Tool",
+        };
+        transcript += &format!("{}: {}\n", author, msg.content);
+    }
+
+    ChatRequest {
+        id,
+        model: model.to_string(),
+        messages: vec![
+            backend::Message {
+                role: backend::Role::System,
+                content: "Summarize the conversation you are given in a few short paragraphs. \
+                    Keep names, numbers, decisions and open questions, drop pleasantries. \
+                    Reply with the summary only."
+                    .to_string(),
+                ..Default::default()
+            },
+            backend::Message {
+                role: backend::Role::User,
+                content: transcript,
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Vec<Message> {
+        [
+            (Author::System, "Be brief."),
+            (Author::User, "Name a colour."),
+            (Author::Bot, "Red."),
+            (
+                Author::User,
+                "Another one, and explain at length why you picked it.",
+            ),
+            (
+                Author::Bot,
+                "Blue, because it is the colour of the sky on a clear day.",
+            ),
+            (Author::User, "And one more?"),
+        ]
+        .into_iter()
+        .map(|(author, content)| Message::new(author, content))
+        .collect()
+    }
+
+    #[test]
+    fn test_cuts_fall_on_turns() {
+        let messages = conversation();
+        let tokenizer = Tokenizer::for_model("llama3");
+
+        assert_eq!(Strategy::Full.start(&messages, &tokenizer), 0);
+        assert_eq!(Strategy::Last { turns: 1 }.start(&messages, &tokenizer), 5);
+        assert_eq!(Strategy::Last { turns: 2 }.start(&messages, &tokenizer), 3);
+        assert_eq!(Strategy::Last { turns: 9 }.start(&messages, &tokenizer), 0);
+
+        // 7 for the system prompt, 14 for the first exchange, 35 for the second, 8 for the last.
+        assert_eq!(
+            Strategy::Window { budget: 64 }.start(&messages, &tokenizer),
+            0
+        );
+        assert_eq!(
+            Strategy::Window { budget: 50 }.start(&messages, &tokenizer),
+            3
+        );
+        assert_eq!(
+            Strategy::Window { budget: 49 }.start(&messages, &tokenizer),
+            5
+        );
+        assert_eq!(
+            Strategy::Window { budget: 1 }.start(&messages, &tokenizer),
+            5
+        );
+
+        assert_eq!(folded(&messages, 3).count(), 2);
+    }
+
+    #[test]
+    fn test_meter() {
+        assert_eq!(meter(1234, Some(4096), false), "ctx ~1.2k/4.1k ▰▰▱▱▱");
+        assert_eq!(meter(900, Some(2000), true), "ctx 900/2k ▰▰▰▱▱");
+        assert_eq!(meter(5000, Some(2000), true), "ctx 5k/2k ▰▰▰▰▰!");
+        assert_eq!(meter(1234, None, false), "ctx ~1.2k");
+
+        let config = Config::default();
+        let args = |args: &str| args.split(' ').map(str::to_string).collect::<Vec<_>>();
+        assert!(matches!(
+            Strategy::parse(&args("window 2048"), &config),
+            Ok(Strategy::Window { budget: 2048 })
+        ));
+        let strategy = Strategy::parse(&args("summarize 2 mock:echo"), &config).unwrap();
+        assert_eq!(strategy.name().unwrap(), "summarize 2 by echo");
+        assert!(Strategy::parse(&args("last 0"), &config).is_err());
+        assert!(Strategy::parse(&args("sliding"), &config).is_err());
+    }
+}
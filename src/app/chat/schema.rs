@@ -0,0 +1,177 @@
+use serde_json::Value;
+
+/// Checks `value` against the subset of JSON schema that structured output schemas are written
+/// in: `type`, `enum`, `const`, `properties`, `required`, `additionalProperties`, `items`, and
+/// the length and range bounds. Unknown keywords are ignored. The error starts with the path of
+/// the failing field, e.g. `$.people[2].age: expected integer, got "ten"`.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    check(schema, value, "$")
+}
+
+fn check(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let fail = |message: String| Err(format!("{}: {}", path, message));
+    // `true`, `{}` and anything else that isn't an object accept every value.
+    let Some(schema) = schema.as_object() else {
+        return match schema {
+            Value::Bool(false) => fail("no value is allowed here".to_string()),
+            _ => Ok(()),
+        };
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types = match expected {
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => expected.as_str().into_iter().collect::<Vec<_>>(),
+        };
+        if !types.is_empty() && !types.iter().any(|kind| has_type(value, kind)) {
+            return fail(format!("expected {}, got {}", types.join(" or "), value));
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            return fail(format!(
+                "{} is not one of {}",
+                value,
+                Value::from(options.clone())
+            ));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return fail(format!("expected {}, got {}", constant, value));
+        }
+    }
+
+    let bound = |key: &str| schema.get(key).and_then(Value::as_f64);
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = bound("minimum").filter(|minimum| number < *minimum) {
+            return fail(format!("{} is less than {}", number, minimum));
+        }
+        if let Some(maximum) = bound("maximum").filter(|maximum| number > *maximum) {
+            return fail(format!("{} is greater than {}", number, maximum));
+        }
+    }
+    let length = match value {
+        Value::String(string) => Some(("minLength", "maxLength", string.chars().count())),
+        Value::Array(items) => Some(("minItems", "maxItems", items.len())),
+        _ => None,
+    };
+    if let Some((min_key, max_key, length)) = length {
+        if let Some(min) = bound(min_key).filter(|min| (length as f64) < *min) {
+            return fail(format!("length {} is shorter than {}", length, min));
+        }
+        if let Some(max) = bound(max_key).filter(|max| (length as f64) > *max) {
+            return fail(format!("length {} is longer than {}", length, max));
+        }
+    }
+
+    if let Value::Object(fields) = value {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !fields.contains_key(required) {
+                return Err(format!("{}.{}: missing required field", path, required));
+            }
+        }
+        for (key, field) in fields {
+            let field_path = format!("{}.{}", path, key);
+            match properties.and_then(|properties| properties.get(key)) {
+                Some(property) => check(property, field, &field_path)?,
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        return Err(format!("{}: unexpected field", field_path));
+                    }
+                    Some(additional) => check(additional, field, &field_path)?,
+                    None => {}
+                },
+            }
+        }
+    }
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            check(item_schema, item, &format!("{}[{}]", path, index))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, kind: &str) -> bool {
+    match kind {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        // JSON has no separate integer type, `1.0` is as much an integer as `1`.
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_reports_path_of_failing_field() {
+        let schema = json!({
+            "type": "object",
+            "required": ["people"],
+            "properties": {
+                "people": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["name", "age"],
+                        "additionalProperties": false,
+                        "properties": {
+                            "name": { "type": "string", "minLength": 1 },
+                            "age": { "type": "integer", "minimum": 0 },
+                            "role": { "enum": ["admin", "user"] },
+                        },
+                    },
+                },
+            },
+        });
+
+        let valid = json!({"people": [{"name": "Ada", "age": 36, "role": "admin"}]});
+        assert_eq!(validate(&schema, &valid), Ok(()));
+        let integral = json!({"people": [{"name": "Ada", "age": 36.0}]});
+        assert_eq!(validate(&schema, &integral), Ok(()));
+
+        let cases = [
+            (json!({}), "$.people: missing required field"),
+            (
+                json!({"people": [{"name": "Ada", "age": 36}, {"name": "Bob", "age": "ten"}]}),
+                "$.people[1].age: expected integer, got \"ten\"",
+            ),
+            (
+                json!({"people": [{"name": "Ada", "age": 36.5}]}),
+                "$.people[0].age: expected integer, got 36.5",
+            ),
+            (
+                json!({"people": [{"name": "Ada", "age": 36, "role": "root"}]}),
+                "$.people[0].role: \"root\" is not one of [\"admin\",\"user\"]",
+            ),
+            (
+                json!({"people": [{"name": "Ada", "age": 36, "email": "a@b"}]}),
+                "$.people[0].email: unexpected field",
+            ),
+            (
+                json!({"people": [{"name": "", "age": 36}]}),
+                "$.people[0].name: length 0 is shorter than 1",
+            ),
+        ];
+        for (value, error) in cases {
+            assert_eq!(validate(&schema, &value), Err(error.to_string()));
+        }
+    }
+}
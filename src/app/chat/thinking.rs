@@ -0,0 +1,81 @@
+const OPEN: &str = "<think>";
+const CLOSE: &str = "</think>";
+
+/// Splits a streamed reply into reasoning and answer, for models that inline their reasoning in
+/// `<think>` tags instead of sending it separately. Tags may be cut anywhere between chunks.
+#[derive(Debug, Default)]
+pub struct ThinkTags {
+    inside: bool,
+    /// The end of the text so far, while it could still be the start of a tag.
+    carry: String,
+}
+
+impl ThinkTags {
+    /// Takes the next piece of content, returns the `(thinking, answer)` text it completes.
+    pub fn feed(&mut self, text: &str) -> (String, String) {
+        let mut text = std::mem::take(&mut self.carry) + text;
+        let (mut thinking, mut answer) = (String::new(), String::new());
+
+        loop {
+            let tag = if self.inside { CLOSE } else { OPEN };
+            let target = if self.inside {
+                &mut thinking
+            } else {
+                &mut answer
+            };
+            match text.find(tag) {
+                Some(start) => {
+                    *target += &text[..start];
+                    text.drain(..start + tag.len());
+                    self.inside = !self.inside;
+                }
+                None => {
+                    let keep = (1..tag.len())
+                        .rev()
+                        .find(|len| text.ends_with(&tag[..*len]))
+                        .unwrap_or(0);
+                    self.carry = text.split_off(text.len() - keep);
+                    *target += &text;
+                    return (thinking, answer);
+                }
+            }
+        }
+    }
+
+    /// Whatever was held back in case it turned into a tag, once the stream is over.
+    pub fn finish(&mut self) -> (String, String) {
+        let rest = std::mem::take(&mut self.carry);
+        match std::mem::take(&mut self.inside) {
+            true => (rest, String::new()),
+            false => (String::new(), rest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_splits_tags_across_chunks() {
+        let mut tags = ThinkTags::default();
+        let mut thinking = String::new();
+        let mut answer = String::new();
+        for chunk in [
+            "<thi",
+            "nk>Hmm, 2+2",
+            " is 4.</th",
+            "ink>\n\nIt's 4",
+            " <",
+            "3",
+        ] {
+            let (more_thinking, more_answer) = tags.feed(chunk);
+            thinking += &more_thinking;
+            answer += &more_answer;
+        }
+        let (rest_thinking, rest_answer) = tags.finish();
+
+        assert_eq!(thinking + &rest_thinking, "Hmm, 2+2 is 4.");
+        assert_eq!(answer + &rest_answer, "\n\nIt's 4 <3");
+    }
+}
@@ -0,0 +1,137 @@
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer as Vocabulary};
+use tiktoken_rs::CoreBPE;
+
+use super::Message;
+
+/// Tokens every message costs on top of its text, for the role and the separators around it.
+const MESSAGE_OVERHEAD: u64 = 4;
+/// What an attached image costs, whatever its size: vision models take from a few hundred to a
+/// couple of thousand tokens per image, depending on the model and the resolution.
+pub const IMAGE_TOKENS: u64 = 768;
+
+/// Counts tokens with a BPE vocabulary, for servers that don't report counts and for prompts that
+/// haven't been sent yet. OpenAI models get their own vocabulary. Other families ship theirs with
+/// the weights rather than as a library, so `o200k_base` stands in for them: modern vocabularies
+/// of that size split text much the same way, and those counts are marked as estimates.
+#[derive(Clone, Copy)]
+pub struct Tokenizer {
+    bpe: &'static CoreBPE,
+    /// Whether this is the model's own vocabulary.
+    pub exact: bool,
+}
+
+impl Tokenizer {
+    pub fn for_model(model: &str) -> Self {
+        let bpe = match get_tokenizer(model) {
+            Some(Vocabulary::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+            Some(Vocabulary::Cl100kBase) => tiktoken_rs::cl100k_base_singleton(),
+            Some(Vocabulary::P50kBase) => tiktoken_rs::p50k_base_singleton(),
+            Some(Vocabulary::P50kEdit) => tiktoken_rs::p50k_edit_singleton(),
+            Some(Vocabulary::R50kBase | Vocabulary::Gpt2) => tiktoken_rs::r50k_base_singleton(),
+            None => {
+                return Self {
+                    bpe: tiktoken_rs::o200k_base_singleton(),
+                    exact: false,
+                }
+            }
+        };
+        Self { bpe, exact: true }
+    }
+
+    /// Token count of `text`. Special tokens in it are counted as the plain text they are.
+    pub fn count(&self, text: &str) -> u64 {
+        self.bpe.encode_ordinary(text).len() as u64
+    }
+
+    /// Token count of `msg` as part of a prompt, attachments included. Its reasoning is never
+    /// sent, so isn't counted. Kept with the message, chats count theirs on every frame.
+    pub fn count_message(&self, msg: &Message) -> u64 {
+        if let Some(tokens) = msg.tokens.get() {
+            return tokens;
+        }
+        let calls = msg
+            .tool_calls
+            .iter()
+            .map(|call| {
+                self.count(&call.function.name) + self.count(&call.function.arguments.to_string())
+            })
+            .sum::<u64>();
+        let images = msg.attachments.len() as u64 * IMAGE_TOKENS;
+        let tokens = MESSAGE_OVERHEAD + self.count(&msg.content) + calls + images;
+        msg.tokens.set(Some(tokens));
+        tokens
+    }
+}
+
+/// `n` in at most four characters or so, e.g. `950`, `1.2k` or `32k`.
+pub fn compact(n: u64) -> String {
+    match n {
+        0..=999 => n.to_string(),
+        _ => format!("{:.1}k", n as f64 / 1000.0).replace(".0k", "k"),
+    }
+}
+
+/// Tokens a chat went through over its lifetime.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub prompt: u64,
+    pub completion: u64,
+    /// Whether any of it is an estimate, neither the server's count nor one with the model's own
+    /// vocabulary.
+    pub estimated: bool,
+}
+
+impl Usage {
+    /// Adds a finished exchange, falling back on our own counts where the server kept quiet. Those
+    /// are estimates unless they were `exact`.
+    pub fn add(&mut self, counted: (Option<u64>, Option<u64>), ours: (u64, u64), exact: bool) {
+        self.prompt += counted.0.unwrap_or(ours.0);
+        self.completion += counted.1.unwrap_or(ours.1);
+        self.estimated |= !exact && (counted.0.is_none() || counted.1.is_none());
+    }
+
+    pub fn total(&self) -> u64 {
+        self.prompt + self.completion
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::{Attachment, Author};
+
+    #[test]
+    fn test_count() {
+        let gpt = Tokenizer::for_model("gpt-4o");
+        let llama = Tokenizer::for_model("llama3:8b");
+        assert!(gpt.exact && !llama.exact);
+        assert!(!Tokenizer::for_model("claude-3-5-sonnet-latest").exact);
+
+        assert_eq!(gpt.count(""), 0);
+        assert_eq!(gpt.count("Hello there"), 2);
+        assert_eq!(
+            Tokenizer::for_model("gpt-4").count("internationalization"),
+            2
+        );
+        assert_eq!(gpt.count("<|endoftext|>"), 7);
+
+        assert_eq!(
+            llama.count_message(&Message::new(Author::User, "What is this?")),
+            8
+        );
+        let mut msg = Message::new(Author::User, "What is this?");
+        msg.attachments.push(Attachment {
+            name: "cat.png".to_string(),
+            data: "iVBORw0KGgo=".to_string(),
+        });
+        assert_eq!(llama.count_message(&msg), 8 + IMAGE_TOKENS);
+
+        let mut usage = Usage::default();
+        usage.add((Some(10), Some(3)), (12, 4), false);
+        usage.add((None, Some(2)), (20, 4), true);
+        assert!(!usage.estimated);
+        usage.add((None, None), (5, 1), false);
+        assert_eq!(usage.total(), 41);
+        assert!(usage.estimated);
+    }
+}
@@ -0,0 +1,271 @@
+use nom::branch::alt;
+use nom::character::complete::{char, multispace0, one_of};
+use nom::combinator::{all_consuming, opt};
+use nom::error::Error;
+use nom::multi::fold_many0;
+use nom::number::complete::double;
+use nom::sequence::{delimited, pair, preceded};
+use nom::{IResult, Parser};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use super::backend::{Job, RequestId, Tool, ToolCall};
+
+/// Longer tool output is cut off, it all ends up in the context window.
+const MAX_OUTPUT: usize = 16 * 1024;
+
+/// What the calls for the request with the given id returned, as `(call id, output)` pairs.
+pub type Ran = (RequestId, Vec<(Option<String>, String)>);
+
+/// The tools offered to chats that have them enabled.
+pub fn specs() -> Vec<Tool> {
+    let path = serde_json::json!({
+        "type": "object",
+        "properties": { "path": { "type": "string" } },
+        "required": ["path"],
+    });
+
+    vec![
+        Tool::function(
+            "read_file",
+            "Reads a text file under the working directory.",
+            path.clone(),
+        ),
+        Tool::function(
+            "list_dir",
+            "Lists the entries of a directory under the working directory, directories end with a \
+             slash.",
+            path,
+        ),
+        Tool::function(
+            "calculator",
+            "Evaluates an arithmetic expression with + - * / % ^ and parentheses.",
+            serde_json::json!({
+                "type": "object",
+                "properties": { "expression": { "type": "string" } },
+                "required": ["expression"],
+            }),
+        ),
+    ]
+}
+
+/// Runs `call` on this machine. Failures are meant for the model to read, so it can correct
+/// itself.
+pub fn run(call: &ToolCall) -> Result<String, String> {
+    let arg = |name: &str| {
+        call.function
+            .arguments
+            .get(name)
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| format!("missing string argument {}", name))
+    };
+
+    match call.function.name.as_str() {
+        "read_file" => read_file(arg("path")?),
+        "list_dir" => list_dir(arg("path")?),
+        "calculator" => calculate(arg("expression")?).map(|value| value.to_string()),
+        name => Err(format!("unknown tool {}", name)),
+    }
+}
+
+/// A job running `calls` off the UI thread, sending their outputs to `tx`. Failures are reported
+/// to the model as the output, so every call gets an answer.
+pub fn job(calls: Vec<ToolCall>, id: RequestId, tx: mpsc::Sender<Ran>) -> Job {
+    Job::local(async move {
+        let ids = calls.iter().map(|call| call.id.clone()).collect::<Vec<_>>();
+        let outputs = tokio::task::spawn_blocking(move || {
+            calls
+                .iter()
+                .map(|call| run(call).unwrap_or_else(|e| format!("error: {}", e)))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_else(|e| vec![format!("error: {}", e); ids.len()]);
+        let _ = tx.send((id, ids.into_iter().zip(outputs).collect()));
+    })
+}
+
+/// Resolves `path` against the working directory, refusing anything outside it, so a model can't
+/// go after keys or the config with its tokens. Symlinks are followed before checking.
+fn confine(path: &str) -> Result<PathBuf, String> {
+    let root = std::env::current_dir()
+        .and_then(|dir| dir.canonicalize())
+        .map_err(|e| e.to_string())?;
+    let path = std::path::Path::new(path)
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path, e))?;
+    match path.starts_with(&root) {
+        true => Ok(path),
+        false => Err(format!(
+            "{} is outside the working directory",
+            path.display()
+        )),
+    }
+}
+
+/// Cuts `content` down to [`MAX_OUTPUT`] bytes, on a character boundary.
+fn truncate(mut content: String) -> String {
+    if content.len() > MAX_OUTPUT {
+        let mut end = MAX_OUTPUT;
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        content.truncate(end);
+        content += "\n[truncated]";
+    }
+    content
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    // Never more than what is kept, whatever the size of the file.
+    let mut bytes = Vec::new();
+    std::fs::File::open(confine(path)?)
+        .and_then(|file| file.take(MAX_OUTPUT as u64 + 1).read_to_end(&mut bytes))
+        .map_err(|e| e.to_string())?;
+
+    let content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        // The limit fell in the middle of a character.
+        Err(e) if e.utf8_error().error_len().is_none() => {
+            let valid = e.utf8_error().valid_up_to();
+            let mut bytes = e.into_bytes();
+            bytes.truncate(valid);
+            String::from_utf8(bytes).map_err(|e| e.to_string())?
+        }
+        Err(_) => return Err(format!("{} is not a text file", path)),
+    };
+    Ok(truncate(content))
+}
+
+fn list_dir(path: &str) -> Result<String, String> {
+    let mut entries = std::fs::read_dir(confine(path)?)
+        .map_err(|e| e.to_string())?
+        .map(|entry| {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let mut name = entry.file_name().to_string_lossy().to_string();
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            Ok(name)
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    entries.sort();
+    Ok(truncate(entries.join("\n")))
+}
+
+fn calculate(expression: &str) -> Result<f64, String> {
+    let (_, value) = all_consuming(delimited(multispace0, sum, multispace0))
+        .parse(expression)
+        .map_err(|e| format!("invalid expression: {}", e))?;
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err(format!("{} is not a finite number", expression))
+    }
+}
+
+fn token<'a, O>(
+    parser: impl Parser<&'a str, O, Error<&'a str>>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    delimited(multispace0, parser, multispace0)
+}
+
+fn sum(input: &str) -> IResult<&str, f64> {
+    let (input, first) = product(input)?;
+    fold_many0(
+        pair(token(one_of("+-")), product),
+        move || first,
+        |acc, (op, value)| match op {
+            '+' => acc + value,
+            _ => acc - value,
+        },
+    )
+    .parse(input)
+}
+
+fn product(input: &str) -> IResult<&str, f64> {
+    let (input, first) = power(input)?;
+    fold_many0(
+        pair(token(one_of("*/%")), power),
+        move || first,
+        |acc, (op, value)| match op {
+            '*' => acc * value,
+            '/' => acc / value,
+            _ => acc % value,
+        },
+    )
+    .parse(input)
+}
+
+/// Right associative, so `2^3^2` is `2^9`.
+fn power(input: &str) -> IResult<&str, f64> {
+    let (input, base) = unary(input)?;
+    let (input, exponent) = opt(preceded(token(char('^')), power)).parse(input)?;
+    Ok((input, exponent.map_or(base, |exponent| base.powf(exponent))))
+}
+
+fn unary(input: &str) -> IResult<&str, f64> {
+    alt((
+        preceded(token(char('-')), unary).map(|value| -value),
+        token(double),
+        delimited(token(char('(')), sum, token(char(')'))),
+    ))
+    .parse(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::chat::backend::FunctionCall;
+
+    fn call(name: &str, arguments: serde_json::Value) -> ToolCall {
+        ToolCall {
+            id: None,
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments,
+            },
+        }
+    }
+
+    #[test]
+    fn test_calculator() {
+        assert_eq!(calculate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(calculate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(calculate("2^3^2 - -1"), Ok(513.0));
+        assert_eq!(calculate("7 % 4 / 2"), Ok(1.5));
+        assert!(calculate("1 / 0").is_err());
+        assert!(calculate("1 +").is_err());
+
+        let result = run(&call(
+            "calculator",
+            serde_json::json!({"expression": "6*7"}),
+        ));
+        assert_eq!(result.as_deref(), Ok("42"));
+    }
+
+    #[test]
+    fn test_bad_calls_are_reported() {
+        assert!(run(&call("rm_rf", serde_json::json!({}))).is_err());
+        assert!(run(&call("read_file", serde_json::json!({"file": "x"}))).is_err());
+        assert!(run(&call("list_dir", serde_json::json!({"path": "src"})))
+            .unwrap()
+            .contains("app/"));
+    }
+
+    #[test]
+    fn test_files_stay_within_the_working_directory() {
+        let read = |path: &str| run(&call("read_file", serde_json::json!({ "path": path })));
+
+        assert!(read("Cargo.toml").unwrap().contains("[package]"));
+        let long = read("src/app/chat.rs").unwrap();
+        assert!(long.ends_with("\n[truncated]"));
+        assert!(long.len() <= MAX_OUTPUT + "\n[truncated]".len());
+
+        for outside in ["/etc/passwd", "../", "/dev/zero"] {
+            assert!(read(outside).is_err(), "{} was read", outside);
+        }
+        assert!(run(&call("list_dir", serde_json::json!({"path": "/"}))).is_err());
+    }
+}
@@ -4,8 +4,12 @@ use crossterm::event::KeyModifiers;
 use ratatui::prelude::*;
 use ratatui::widgets::*;
 
+use super::chat::Attachment;
+
 pub struct Input {
     pub input: String,
+    /// Sent along with the next message.
+    pub attachments: Vec<Attachment>,
 }
 
 impl Default for Input {
@@ -18,6 +22,7 @@ impl Input {
     pub fn new() -> Self {
         Self {
             input: String::new(),
+            attachments: Vec::new(),
         }
     }
 
@@ -40,9 +45,11 @@ impl Input {
 impl StatefulWidget for &Input {
     type State = super::State;
     fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
-        let blocked_area = Block::bordered()
-            .title("Input")
-            .padding(Padding::uniform(1));
+        let mut title = Line::from("Input");
+        for attachment in &self.attachments {
+            title.push_span(Span::raw(format!(" [{}]", attachment.name)).cyan());
+        }
+        let blocked_area = Block::bordered().title(title).padding(Padding::uniform(1));
 
         let input_area = Paragraph::new(self.input.as_str()).block(blocked_area);
 
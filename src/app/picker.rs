@@ -0,0 +1,172 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::prelude::*;
+use ratatui::widgets::*;
+use std::collections::BTreeSet;
+use std::sync::mpsc;
+
+use super::chat::{http_client, list_models, ollama_url, Job, ModelInfo, Order};
+use crate::config::Config;
+
+type Listing = Result<Vec<ModelInfo>, String>;
+
+/// Overlay listing the models of an Ollama endpoint, from which chats can be created.
+pub struct ModelPicker {
+    /// As given to `/models`, `None` for the configured server.
+    pub endpoint: Option<String>,
+    pub models: Option<Listing>,
+    pub selected: usize,
+    pub chosen: BTreeSet<usize>,
+
+    requested: bool,
+    channel: (mpsc::Sender<Listing>, mpsc::Receiver<Listing>),
+}
+
+/// What the app should do after the picker handled a key.
+pub enum Pick {
+    Stay,
+    Close,
+    /// Close and create a chat for each of these `/create` specs.
+    Create(Vec<String>),
+}
+
+impl ModelPicker {
+    pub fn new(endpoint: Option<String>) -> Self {
+        Self {
+            endpoint,
+            models: None,
+            selected: 0,
+            chosen: BTreeSet::new(),
+            requested: false,
+            channel: mpsc::channel(),
+        }
+    }
+
+    pub fn reconsile(&mut self, config: &Config, request_handle: mpsc::Sender<Order>) {
+        if !self.requested {
+            self.requested = true;
+
+            let base_url = ollama_url(self.endpoint.as_deref(), config);
+            let tx = self.channel.0.clone();
+            let job = match http_client(config, &base_url) {
+                Ok(client) => Job::on(base_url.clone(), async move {
+                    let models = list_models(&client, &base_url)
+                        .await
+                        .map_err(|e| format!("Failed to list models at {}: {}", base_url, e));
+                    let _ = tx.send(models);
+                }),
+                Err(e) => {
+                    self.models = Some(Err(e));
+                    return;
+                }
+            };
+            request_handle.send(Order::Spawn(job)).unwrap();
+        }
+
+        if let Ok(models) = self.channel.1.try_recv() {
+            self.models = Some(models);
+        }
+    }
+
+    pub fn on_key(&mut self, key: KeyEvent) -> Pick {
+        let count = match &self.models {
+            Some(Ok(models)) => models.len(),
+            _ => 0,
+        };
+
+        match key.code {
+            KeyCode::Esc => Pick::Close,
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected = self.selected.saturating_sub(1);
+                Pick::Stay
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(count.saturating_sub(1));
+                Pick::Stay
+            }
+            KeyCode::Char(' ') if count > 0 => {
+                if !self.chosen.remove(&self.selected) {
+                    self.chosen.insert(self.selected);
+                }
+                Pick::Stay
+            }
+            KeyCode::Enter if count > 0 => {
+                let picked = match self.chosen.is_empty() {
+                    true => vec![self.selected],
+                    false => self.chosen.iter().copied().collect(),
+                };
+                Pick::Create(picked.into_iter().map(|i| self.spec(i)).collect())
+            }
+            _ => Pick::Stay,
+        }
+    }
+
+    fn spec(&self, index: usize) -> String {
+        let name = match &self.models {
+            Some(Ok(models)) => models[index].name.as_str(),
+            _ => unreachable!("nothing to pick from"),
+        };
+        match &self.endpoint {
+            Some(endpoint) => format!("{}@{}", name, endpoint),
+            None => name.to_string(),
+        }
+    }
+}
+
+impl Widget for &ModelPicker {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let title = format!(
+            "Models{} (↑/↓ move, space select, enter create, esc close)",
+            self.endpoint
+                .as_ref()
+                .map(|endpoint| format!(" @ {}", endpoint))
+                .unwrap_or_default()
+        );
+        let block = Block::bordered().title(title);
+
+        Widget::render(Clear, area, buf);
+
+        let models = match &self.models {
+            None => {
+                Widget::render(Paragraph::new("Loading...").block(block), area, buf);
+                return;
+            }
+            Some(Err(e)) => {
+                Widget::render(Paragraph::new(e.as_str().red()).block(block), area, buf);
+                return;
+            }
+            Some(Ok(models)) => models,
+        };
+
+        let rows = models.iter().enumerate().map(|(i, model)| {
+            let mark = match self.chosen.contains(&i) {
+                true => "[x]",
+                false => "[ ]",
+            };
+            Row::new([
+                mark.to_string(),
+                model.name.clone(),
+                format!("{:.1} GB", model.size as f64 / 1e9),
+                model.details.family.clone(),
+                model.details.parameter_size.clone(),
+                model.details.quantization_level.clone(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(3),
+                Constraint::Fill(3),
+                Constraint::Length(9),
+                Constraint::Fill(1),
+                Constraint::Length(7),
+                Constraint::Length(8),
+            ],
+        )
+        .header(Row::new(["", "Name", "Size", "Family", "Params", "Quant"]).bold())
+        .highlight_style(Style::default().reversed())
+        .block(block);
+
+        let mut state = TableState::default().with_selected(Some(self.selected));
+        StatefulWidget::render(table, area, buf, &mut state);
+    }
+}
@@ -0,0 +1,140 @@
+use ratatui::prelude::*;
+use ratatui::widgets::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+
+use super::chat::{http_client, ollama_url, pull, Job, Order, PullProgress};
+use crate::config::Config;
+
+type Progress = Result<PullProgress, String>;
+
+/// A model being downloaded onto an Ollama server through `/pull`.
+pub struct Pull {
+    /// `model[@endpoint]`, as given to `/pull` and as `/create` would take it.
+    pub spec: String,
+    pub progress: Option<PullProgress>,
+    pub state: PullState,
+
+    requested: bool,
+    channel: (mpsc::Sender<Progress>, mpsc::Receiver<Progress>),
+}
+
+#[derive(Clone, PartialEq)]
+pub enum PullState {
+    Running,
+    Done,
+    Failed(String),
+}
+
+impl Pull {
+    pub fn new(spec: &str) -> Self {
+        Self {
+            spec: spec.to_string(),
+            progress: None,
+            state: PullState::Running,
+            requested: false,
+            channel: mpsc::channel(),
+        }
+    }
+
+    /// Sends off the pull if it hasn't been yet and applies whatever progress came back.
+    pub fn reconsile(&mut self, config: &Config, request_handle: mpsc::Sender<Order>) {
+        if !self.requested {
+            self.requested = true;
+
+            let (model, endpoint) = match self.spec.rsplit_once('@') {
+                Some((model, endpoint)) => (model.to_string(), Some(endpoint)),
+                None => (self.spec.clone(), None),
+            };
+            let base_url = ollama_url(endpoint, config);
+            let tx = self.channel.0.clone();
+            let client = match http_client(config, &base_url) {
+                Ok(client) => client,
+                Err(e) => {
+                    self.state = PullState::Failed(e);
+                    return;
+                }
+            };
+
+            let job = Job::on(base_url.clone(), async move {
+                let progress_tx = tx.clone();
+                let succeeded = AtomicBool::new(false);
+                let result = pull(&client, &base_url, &model, |progress| {
+                    succeeded.fetch_or(progress.is_success(), Ordering::Relaxed);
+                    let _ = progress_tx.send(Ok(progress));
+                })
+                .await;
+                // Whatever happens, the pull has to end up done or failed.
+                match result {
+                    Err(e) => {
+                        let _ = tx.send(Err(e.to_string()));
+                    }
+                    Ok(()) if !succeeded.load(Ordering::Relaxed) => {
+                        let _ = tx.send(Err("the server ended the pull unfinished".to_string()));
+                    }
+                    Ok(()) => {}
+                }
+            });
+            request_handle.send(Order::Spawn(job)).unwrap();
+        }
+
+        for update in self.channel.1.try_iter() {
+            match update {
+                Ok(progress) => {
+                    if progress.is_success() {
+                        self.state = PullState::Done;
+                    }
+                    self.progress = Some(progress);
+                }
+                Err(e) => self.state = PullState::Failed(e),
+            }
+        }
+    }
+
+    fn ratio(&self) -> f64 {
+        match (&self.state, &self.progress) {
+            (PullState::Done, _) => 1.0,
+            (
+                _,
+                Some(PullProgress {
+                    total: Some(total),
+                    completed,
+                    ..
+                }),
+            ) if *total > 0 => (completed.unwrap_or(0) as f64 / *total as f64).clamp(0.0, 1.0),
+            _ => 0.0,
+        }
+    }
+}
+
+impl Widget for &Pull {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let status = self
+            .progress
+            .as_ref()
+            .map(|progress| progress.status.as_str())
+            .unwrap_or("starting");
+
+        let (label, style) = match &self.state {
+            PullState::Running => (
+                format!("{} {:.0}%", status, self.ratio() * 100.0),
+                Style::default().fg(Color::Cyan),
+            ),
+            PullState::Done => (
+                format!("done, /create {}", self.spec),
+                Style::default().fg(Color::Green),
+            ),
+            PullState::Failed(e) => (e.clone(), Style::default().fg(Color::Red)),
+        };
+
+        Widget::render(
+            Gauge::default()
+                .block(Block::bordered().title(self.spec.as_str()))
+                .gauge_style(style)
+                .ratio(self.ratio())
+                .label(label),
+            area,
+            buf,
+        );
+    }
+}
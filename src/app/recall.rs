@@ -0,0 +1,271 @@
+use crossterm::event::{KeyCode, KeyEvent};
+use ratatui::prelude::*;
+use ratatui::widgets::*;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::mpsc;
+
+use super::chat::{embed, http_client, ollama_url, Author, Chat, Job, Order};
+use crate::config::Config;
+
+/// How many of the closest messages are listed.
+const TOP_HITS: usize = 10;
+
+/// Embeddings by message content, kept for the whole session so each message is embedded once.
+pub type EmbeddingCache = HashMap<String, Vec<f32>>;
+
+/// The query's embedding, and those of the contents that weren't cached yet.
+type Embedded = Result<(Vec<f32>, Vec<(String, Vec<f32>)>), String>;
+
+/// A message as it was when `/recall` was issued.
+pub struct Passage {
+    pub chat: String,
+    /// Index of the message in its chat.
+    pub position: usize,
+    pub author: Author,
+    pub content: String,
+}
+
+pub struct Hit {
+    /// Index into the passages.
+    pub passage: usize,
+    pub score: f32,
+}
+
+/// Overlay listing the messages of all chats closest in meaning to a query.
+pub struct Recall {
+    pub query: String,
+    pub passages: Vec<Passage>,
+    pub hits: Option<Result<Vec<Hit>, String>>,
+    pub selected: usize,
+
+    requested: bool,
+    channel: (mpsc::Sender<Embedded>, mpsc::Receiver<Embedded>),
+}
+
+impl Recall {
+    pub fn new(query: String, chats: &[Chat]) -> Self {
+        let passages = chats
+            .iter()
+            .flat_map(|chat| {
+                chat.messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, msg)| !msg.content.trim().is_empty())
+                    .map(|(position, msg)| Passage {
+                        chat: chat.name.clone(),
+                        position,
+                        author: msg.author.clone(),
+                        content: msg.content.clone(),
+                    })
+            })
+            .collect();
+
+        Self {
+            query,
+            passages,
+            hits: None,
+            selected: 0,
+            requested: false,
+            channel: mpsc::channel(),
+        }
+    }
+
+    pub fn reconsile(
+        &mut self,
+        config: &Config,
+        cache: &mut EmbeddingCache,
+        request_handle: mpsc::Sender<Order>,
+    ) {
+        if !self.requested {
+            self.requested = true;
+
+            let missing = self
+                .passages
+                .iter()
+                .map(|passage| &passage.content)
+                .filter(|content| !cache.contains_key(*content))
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            // One round trip for everything, the query goes first.
+            let input = std::iter::once(self.query.clone())
+                .chain(missing)
+                .collect::<Vec<_>>();
+
+            let base_url = ollama_url(None, config);
+            let model = config.embedding_model.clone();
+            let tx = self.channel.0.clone();
+            let job = match http_client(config, &base_url) {
+                Ok(client) => Job::on(base_url.clone(), async move {
+                    let embedded = embed(&client, &base_url, &model, &input)
+                        .await
+                        .map(|mut embeddings| {
+                            let query = embeddings.remove(0);
+                            (query, input.into_iter().skip(1).zip(embeddings).collect())
+                        })
+                        .map_err(|e| format!("Failed to embed with {}: {}", model, e));
+                    let _ = tx.send(embedded);
+                }),
+                Err(e) => {
+                    self.hits = Some(Err(e));
+                    return;
+                }
+            };
+            request_handle.send(Order::Spawn(job)).unwrap();
+        }
+
+        if let Ok(embedded) = self.channel.1.try_recv() {
+            self.hits = Some(embedded.map(|(query, fresh)| {
+                cache.extend(fresh);
+                rank(&query, &self.passages, cache)
+            }));
+        }
+    }
+
+    /// Returns whether the overlay should close.
+    pub fn on_key(&mut self, key: KeyEvent) -> bool {
+        let count = match &self.hits {
+            Some(Ok(hits)) => hits.len(),
+            _ => 0,
+        };
+
+        match key.code {
+            KeyCode::Esc | KeyCode::Enter => return true,
+            KeyCode::Up | KeyCode::Char('k') => {
+                self.selected = self.selected.saturating_sub(1);
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(count.saturating_sub(1));
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+/// The [`TOP_HITS`] passages closest to `query`, best first.
+fn rank(query: &[f32], passages: &[Passage], cache: &EmbeddingCache) -> Vec<Hit> {
+    let mut hits = passages
+        .iter()
+        .enumerate()
+        .filter_map(|(index, passage)| {
+            let embedding = cache.get(&passage.content)?;
+            Some(Hit {
+                passage: index,
+                score: cosine_similarity(query, embedding),
+            })
+        })
+        .collect::<Vec<_>>();
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(TOP_HITS);
+    hits
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+impl Widget for &Recall {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let title = format!("Recall: {} (↑/↓ move, esc close)", self.query);
+        let block = Block::bordered().title(title);
+
+        Widget::render(Clear, area, buf);
+
+        let hits = match &self.hits {
+            None => {
+                let loading = format!("Embedding {} messages...", self.passages.len());
+                Widget::render(Paragraph::new(loading).block(block), area, buf);
+                return;
+            }
+            Some(Err(e)) => {
+                Widget::render(Paragraph::new(e.as_str().red()).block(block), area, buf);
+                return;
+            }
+            Some(Ok(hits)) if hits.is_empty() => {
+                Widget::render(
+                    Paragraph::new("Nothing to recall yet").block(block),
+                    area,
+                    buf,
+                );
+                return;
+            }
+            Some(Ok(hits)) => hits,
+        };
+
+        let rows = hits.iter().map(|hit| {
+            let passage = &self.passages[hit.passage];
+            let author = match passage.author {
+                Author::System => "System",
+                Author::User => "User",
+                Author::Bot => "Bot",
+                Author::Tool => "Tool",
+            };
+            Row::new([
+                format!("{:.2}", hit.score),
+                passage.chat.clone(),
+                format!("#{}", passage.position + 1),
+                author.to_string(),
+                passage
+                    .content
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(5),
+                Constraint::Fill(1),
+                Constraint::Length(5),
+                Constraint::Length(6),
+                Constraint::Fill(4),
+            ],
+        )
+        .header(Row::new(["Score", "Chat", "Pos", "Author", "Message"]).bold())
+        .highlight_style(Style::default().reversed())
+        .block(block);
+
+        let mut state = TableState::default().with_selected(Some(self.selected));
+        StatefulWidget::render(table, area, buf, &mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passage(content: &str) -> Passage {
+        Passage {
+            chat: "llama3".to_string(),
+            position: 0,
+            author: Author::Bot,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_rank_by_cosine_similarity() {
+        let passages = [passage("north"), passage("east"), passage("north-east")];
+        let cache = EmbeddingCache::from([
+            ("north".to_string(), vec![0.0, 2.0]),
+            ("east".to_string(), vec![1.0, 0.0]),
+            ("north-east".to_string(), vec![1.0, 1.0]),
+        ]);
+
+        let hits = rank(&[0.1, 1.0], &passages, &cache);
+
+        let order = hits.iter().map(|hit| hit.passage).collect::<Vec<_>>();
+        assert_eq!(order, [0, 2, 1]);
+        assert!((hits[0].score - 0.995).abs() < 1e-3);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
@@ -1,22 +1,18 @@
-use core::mem::swap;
-use core::str::FromStr;
 use std::sync::mpsc;
 
-use nom::bytes::complete::{escaped, tag};
-use nom::character::complete::{alphanumeric1, one_of};
+use nom::bytes::complete::{escaped, is_not, tag};
+use nom::character::complete::one_of;
 use nom::combinator::{cut, opt};
 use nom::error::{convert_error, ContextError, ParseError, VerboseError};
 use nom::multi::separated_list0;
 use nom::{branch, sequence, IResult, Parser};
 
-use super::chat::{Chat, ChatRequest, ChatResponse, Message};
+use super::chat::{parse_option, Attachment, Chat, Format, Message, Order, Strategy};
+use super::pull::{Pull, PullState};
 use super::{App, Signal};
 
 impl App {
-    pub fn reconsile(
-        &mut self,
-        request_handler: mpsc::Sender<(mpsc::Sender<ChatResponse>, ChatRequest)>,
-    ) {
+    pub fn reconsile(&mut self, request_handler: mpsc::Sender<Order>) {
         let current = self.buffer.pop_front();
         if let Some(current) = current {
             let entry = root_parser::<VerboseError<&str>>(&current);
@@ -32,7 +28,30 @@ impl App {
             }
         }
 
+        for pull in self.pulls.iter_mut() {
+            pull.reconsile(&self.config, request_handler.clone());
+        }
+        // A finished pull leaves once it offered to create its chat, which waits for the input to
+        // be empty so nothing typed is clobbered. A failed one stays until dismissed.
+        self.pulls.retain(|pull| match &pull.state {
+            PullState::Running | PullState::Failed(_) => true,
+            PullState::Done if !self.input.input.is_empty() => true,
+            PullState::Done => {
+                self.input.input = format!("/create {}", pull.spec);
+                false
+            }
+        });
+
+        if let Some(picker) = &mut self.picker {
+            picker.reconsile(&self.config, request_handler.clone());
+        }
+
+        if let Some(recall) = &mut self.recall {
+            recall.reconsile(&self.config, &mut self.embeddings, request_handler.clone());
+        }
+
         self.chats.iter_mut().for_each(|chat| {
+            chat.set_draft(&self.input.input, &self.input.attachments);
             chat.reconsile(request_handler.clone());
         });
     }
@@ -52,11 +71,33 @@ impl Entry {
                     app.send.send(Signal::Exit).unwrap();
                 }
                 Command::CreateChat => {
-                    let name = args.first();
-                    if let Some(name) = name {
-                        app.chats.push(Chat::new(name))
-                    } else {
-                        app.errors.push("Chat name is required".to_string());
+                    // Names are how every other command picks a chat, so they have to be unique.
+                    let (spec, name) = match args.as_slice() {
+                        [spec] => (spec, spec),
+                        [spec, keyword, name] if keyword == "as" => (spec, name),
+                        [] => {
+                            app.errors.push("Chat name is required".to_string());
+                            return;
+                        }
+                        _ => {
+                            app.errors
+                                .push("Usage: /create <model> [as <name>]".to_string());
+                            return;
+                        }
+                    };
+                    if app.chats.iter().any(|chat| chat.name == *name) {
+                        app.errors.push(format!(
+                            "A chat named {} already exists, try /create {} as <name>",
+                            name, spec
+                        ));
+                        return;
+                    }
+                    match Chat::from_spec(spec, &app.config) {
+                        Ok(mut chat) => {
+                            chat.name = name.clone();
+                            app.chats.push(chat);
+                        }
+                        Err(e) => app.errors.push(e),
                     }
                 }
                 Command::DeleteChat => {
@@ -67,23 +108,139 @@ impl Entry {
                         app.errors.push("Chat name is required".to_string());
                     }
                 }
+                Command::Models => app.open_picker(args.first().cloned()),
+                Command::Pull => {
+                    // Failed pulls make room for the next one, without a model they are dismissed.
+                    app.pulls
+                        .retain(|pull| !matches!(pull.state, PullState::Failed(_)));
+                    if let Some(name) = args.first() {
+                        app.pulls.retain(|pull| pull.spec != *name);
+                        app.pulls.push(Pull::new(name));
+                    }
+                }
+                Command::Recall => match args.join(" ") {
+                    query if query.is_empty() => app.errors.push("Query is required".to_string()),
+                    query => app.open_recall(query),
+                },
+                Command::Attach => match args.first() {
+                    Some(path) => match Attachment::load(path) {
+                        Ok(attachment) => app.input.attachments.push(attachment),
+                        Err(e) => app.errors.push(e),
+                    },
+                    // Without a path, the pending attachments are dropped.
+                    None => app.input.attachments.clear(),
+                },
+                Command::Set => match args.as_slice() {
+                    [name, key, values @ ..] => {
+                        let Some(chat) = app.chats.iter_mut().find(|chat| chat.name == *name)
+                        else {
+                            app.errors.push(format!("No chat named {}", name));
+                            return;
+                        };
+                        if values.is_empty() {
+                            chat.options.remove(key);
+                            return;
+                        }
+                        match parse_option(key, values) {
+                            Ok(value) => {
+                                chat.options.insert(key.clone(), value);
+                            }
+                            Err(e) => app.errors.push(e),
+                        }
+                    }
+                    _ => app
+                        .errors
+                        .push("Usage: /set <chat> <option> [value...]".to_string()),
+                },
+                Command::Tools => match args.as_slice() {
+                    [name, state @ ..] => {
+                        let Some(chat) = app.chats.iter_mut().find(|chat| chat.name == *name)
+                        else {
+                            app.errors.push(format!("No chat named {}", name));
+                            return;
+                        };
+                        match state.first().map(String::as_str) {
+                            None => chat.tools = !chat.tools,
+                            Some("on") => chat.tools = true,
+                            Some("off") => chat.tools = false,
+                            Some(other) => app
+                                .errors
+                                .push(format!("Expected on or off, got {}", other)),
+                        }
+                    }
+                    [] => app.errors.push("Chat name is required".to_string()),
+                },
+                Command::Format => match args.as_slice() {
+                    [name, format] => {
+                        let Some(chat) = app.chats.iter_mut().find(|chat| chat.name == *name)
+                        else {
+                            app.errors.push(format!("No chat named {}", name));
+                            return;
+                        };
+                        if format == "off" {
+                            chat.format = None;
+                            return;
+                        }
+                        match Format::load(format) {
+                            Ok(format) => chat.format = Some(format),
+                            Err(e) => app.errors.push(e),
+                        }
+                    }
+                    _ => app
+                        .errors
+                        .push("Usage: /format <chat> json|off|<schema file>".to_string()),
+                },
+                Command::Context => match args.split_first() {
+                    Some((name, strategy)) => {
+                        let Some(chat) = app.chats.iter_mut().find(|chat| chat.name == *name)
+                        else {
+                            app.errors.push(format!("No chat named {}", name));
+                            return;
+                        };
+                        match Strategy::parse(strategy, &app.config) {
+                            Ok(strategy) => chat.context = strategy,
+                            Err(e) => app.errors.push(e),
+                        }
+                    }
+                    None => app.errors.push("Chat name is required".to_string()),
+                },
+                Command::System => {
+                    // `@chat` picks a single chat, without it the prompt goes to all of them.
+                    let (target, words) = match args.split_first() {
+                        Some((first, rest)) if first.starts_with('@') => (Some(&first[1..]), rest),
+                        _ => (None, args.as_slice()),
+                    };
+                    if let Some(name) = target {
+                        if !app.chats.iter().any(|chat| chat.name == name) {
+                            app.errors.push(format!("No chat named {}", name));
+                            return;
+                        }
+                    }
+
+                    let prompt = words.join(" ");
+                    let prompt = (!prompt.is_empty()).then_some(prompt.as_str());
+                    app.chats
+                        .iter_mut()
+                        .filter(|chat| target.is_none_or(|name| chat.name == name))
+                        .for_each(|chat| chat.set_system(prompt));
+                }
+                Command::Stop => match args.first() {
+                    Some(name) if !app.chats.iter().any(|chat| chat.name == *name) => {
+                        app.errors.push(format!("No chat named {}", name));
+                    }
+                    name => app.stop(name.map(String::as_str)),
+                },
                 Command::Clear => {
-                    app.chats.iter_mut().for_each(|chat| {
-                        chat.messages.clear();
-                        chat.triggered = false;
-                        chat.locked = false;
-                        let mut my_channel = mpsc::channel();
-
-                        swap(&mut chat.channel, &mut my_channel);
-                        drop(my_channel);
-                    });
+                    app.chats.iter_mut().for_each(Chat::clear);
                 }
             },
             Entry::Message { message } => {
+                let attachments = std::mem::take(&mut app.input.attachments);
                 app.chats.iter_mut().for_each(|chat| {
                     if !chat.locked {
-                        chat.messages
-                            .push(Message::new(super::chat::Author::User, &message));
+                        let mut user = Message::new(super::chat::Author::User, &message);
+                        user.attachments = attachments.clone();
+                        chat.messages.push(user);
                         chat.triggered = true;
                     }
                 });
@@ -98,7 +255,7 @@ pub fn root_parser<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     branch::alt((
         command_parser.map(|(command, args)| Entry::Command {
             command,
-            args: args.iter().map(|s| s.to_string()).collect(),
+            args: args.iter().map(|s| unescape(s)).collect(),
         }),
         message_parser.map(|message| Entry::Message {
             message: message.to_string(),
@@ -112,6 +269,16 @@ pub enum Command {
     Exit,
     CreateChat,
     DeleteChat,
+    Models,
+    Pull,
+    Recall,
+    Attach,
+    Context,
+    Format,
+    Set,
+    System,
+    Tools,
+    Stop,
     Clear,
 }
 
@@ -123,6 +290,16 @@ impl Command {
             tag("exit").map(|_| Command::Exit),
             tag("create").map(|_| Command::CreateChat),
             tag("delete").map(|_| Command::DeleteChat),
+            tag("models").map(|_| Command::Models),
+            tag("pull").map(|_| Command::Pull),
+            tag("recall").map(|_| Command::Recall),
+            tag("attach").map(|_| Command::Attach),
+            tag("context").map(|_| Command::Context),
+            tag("format").map(|_| Command::Format),
+            tag("set").map(|_| Command::Set),
+            tag("system").map(|_| Command::System),
+            tag("tools").map(|_| Command::Tools),
+            tag("stop").map(|_| Command::Stop),
             tag("brainwash").map(|_| Command::Clear),
         ))
         .parse(input)
@@ -155,5 +332,102 @@ fn command_parser<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
 fn string_parser<'a, E: ParseError<&'a str> + ContextError<&'a str>>(
     input: &'a str,
 ) -> IResult<&'a str, &'a str, E> {
-    escaped(alphanumeric1, '\\', one_of("\"\\ ")).parse(input)
+    escaped(is_not(" \\"), '\\', one_of("\"\\ nt")).parse(input)
+}
+
+/// Resolves the escapes accepted by [`string_parser`].
+fn unescape(arg: &str) -> String {
+    let mut out = String::with_capacity(arg.len());
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_args_are_unescaped() {
+        let (_, entry) =
+            root_parser::<VerboseError<&str>>(r"/set llama3 stop \n\n User\ said").unwrap();
+        match entry {
+            Entry::Command {
+                command: Command::Set,
+                args,
+            } => assert_eq!(args, ["llama3", "stop", "\n\n", "User said"]),
+            other => panic!("unexpected entry: {:?}", other),
+        }
+    }
+    #[test]
+    fn test_chat_names_are_unique() {
+        let mut app = App::new(mpsc::channel().0);
+        app.errors.clear();
+        let run = |app: &mut App, line: &str| {
+            let (_, entry) = root_parser::<VerboseError<&str>>(line).unwrap();
+            entry.reconsile(app);
+        };
+
+        run(&mut app, "/create mock:echo");
+        run(&mut app, "/create mock:echo");
+        assert_eq!(app.chats.len(), 1);
+        assert!(app.errors[0].contains("already exists"));
+
+        run(&mut app, "/create mock:echo as cold");
+        run(&mut app, "/tools cold");
+        let names = app
+            .chats
+            .iter()
+            .map(|chat| chat.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["mock:echo", "cold"]);
+        assert!(!app.chats[0].tools && app.chats[1].tools);
+        assert_eq!(app.chats[1].model, "echo");
+    }
+    #[test]
+    fn test_pulls_leave_once_offered_or_dismissed() {
+        let (orders, _rx) = mpsc::channel();
+        let mut app = App::new(mpsc::channel().0);
+        app.errors.clear();
+        for (spec, state) in [
+            ("llama3", PullState::Done),
+            ("qwen2", PullState::Failed("not found".to_string())),
+        ] {
+            let mut pull = Pull::new(spec);
+            pull.state = state;
+            app.pulls.push(pull);
+        }
+
+        // The offer to create the chat waits for the input to be free.
+        app.input.input = "half a thought".to_string();
+        app.reconsile(orders.clone());
+        assert_eq!(app.pulls.len(), 2);
+
+        app.input.input.clear();
+        app.reconsile(orders.clone());
+        assert_eq!(app.input.input, "/create llama3");
+        let specs = app
+            .pulls
+            .iter()
+            .map(|pull| pull.spec.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(specs, ["qwen2"]);
+
+        // The failure stays on screen until `/pull` dismisses it.
+        app.buffer.push_back("/pull".to_string());
+        app.reconsile(orders.clone());
+        assert!(app.pulls.is_empty());
+        assert!(app.errors.is_empty());
+    }
 }
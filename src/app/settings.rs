@@ -21,7 +21,7 @@ impl Settings {
 
 impl StatefulWidget for &Settings {
     type State = super::State;
-    fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
+    fn render(self, area: Rect, buf: &mut Buffer, _state: &mut Self::State) {
         let settings_layout = Layout::default()
             .direction(Direction::Vertical)
             .constraints([Constraint::Length(20), Constraint::Min(1)].as_ref())
@@ -1,11 +1,54 @@
-use std::collections::HashMap;
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub address: String,
     pub port: u16,
 
     pub hinting: bool,
+
+    /// Seconds to wait for a TCP/TLS connection to a backend.
+    pub connect_timeout: u64,
+    /// Seconds to wait for the first token, which includes the time a model takes to load.
+    pub first_token_timeout: u64,
+    /// Seconds a stream may go quiet between two chunks before it is considered stalled.
+    pub stall_timeout: u64,
+    /// How many more times a request failing before its first token is attempted, at most 16.
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubling with every further attempt up to a minute.
+    pub retry_backoff: u64,
+    /// Requests sent to one endpoint at a time, pulls and embeddings included, the rest wait their
+    /// turn. `1` makes each server answer chats one after the other, `0` lifts the limit.
+    pub concurrency: usize,
+
+    /// Ollama model `/recall` embeds messages with, on the configured server.
+    pub embedding_model: String,
+
+    /// Whether every streamed exchange is written to `fixtures`, for `replay:` chats to serve.
+    pub record: bool,
+    /// Directory recorded exchanges are kept in.
+    pub fixtures: String,
+
+    /// Connection settings for the endpoints whose base URL matches the key, e.g.
+    /// `"https://ollama.internal"` or `"gpu-box:11434"`, see [`Config::endpoint`].
+    pub endpoints: BTreeMap<String, Endpoint>,
+}
+
+/// How to reach an endpoint that sits behind a proxy, or wants credentials of its own.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Endpoint {
+    /// Sent with every request, on top of the provider's own.
+    pub headers: BTreeMap<String, String>,
+    /// Environment variable holding a token sent as `Authorization: Bearer <token>`.
+    pub token_env: Option<String>,
+    /// Proxy requests go through, e.g. `http://proxy.internal:3128`.
+    pub proxy: Option<String>,
+    /// PEM file with the certificate authorities to trust besides the system ones.
+    pub ca_bundle: Option<String>,
 }
 
 impl Default for Config {
@@ -15,8 +58,94 @@ impl Default for Config {
             port: 11434,
 
             hinting: true,
+
+            connect_timeout: 10,
+            first_token_timeout: 300,
+            stall_timeout: 60,
+            retries: 3,
+            retry_backoff: 500,
+            concurrency: 0,
+
+            embedding_model: "nomic-embed-text".to_string(),
+
+            record: false,
+            fixtures: "./fixtures".to_string(),
+
+            endpoints: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub const PATH_VAR: &'static str = "MULTI_AI_CONFIG";
+    pub const DEFAULT_PATH: &'static str = "./config.json";
+    /// Most `retries` a config may ask for, the backoff has long stopped doubling by then.
+    pub const MAX_RETRIES: u32 = 16;
+
+    /// Reads the JSON config at `$MULTI_AI_CONFIG`, or `./config.json`. A missing file is not an
+    /// error, every key falls back to its default.
+    pub fn load() -> Result<Self, String> {
+        let path = std::env::var(Self::PATH_VAR).unwrap_or_else(|_| Self::DEFAULT_PATH.to_string());
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| format!("Failed to parse config {}: {}", path, e))
+                .and_then(|config: Self| config.checked())
+                .map_err(|e| format!("Invalid config {}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("Failed to read config {}: {}", path, e)),
         }
     }
+
+    /// Rejects settings the runtime can't honour.
+    pub fn checked(self) -> Result<Self, String> {
+        if self.retries > Self::MAX_RETRIES {
+            return Err(format!(
+                "retries is {}, at most {} are allowed",
+                self.retries,
+                Self::MAX_RETRIES
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Base URL of the Ollama server used by chats that don't name their own endpoint.
+    pub fn base_url(&self) -> String {
+        format!("http://{}:{}", self.address, self.port)
+    }
+
+    /// Settings of the endpoint at `base_url`, the longest matching key wins. The host has to be
+    /// the same, and so do the scheme, the port and whole leading path segments where the key
+    /// gives them. Keys without a scheme match any, and then without a port any port too.
+    pub fn endpoint(&self, base_url: &str) -> Option<&Endpoint> {
+        let url = Url::parse(base_url).ok()?;
+        self.endpoints
+            .iter()
+            .filter(|(key, _)| endpoint_matches(key, &url))
+            .max_by_key(|(key, _)| key.len())
+            .map(|(_, endpoint)| endpoint)
+    }
+}
+
+fn endpoint_matches(key: &str, url: &Url) -> bool {
+    let scheme = key.contains("://");
+    let Ok(key) = Url::parse(&match scheme {
+        true => key.to_string(),
+        false => format!("http://{}", key),
+    }) else {
+        return false;
+    };
+    let path = key.path().trim_end_matches('/');
+
+    (!scheme || key.scheme() == url.scheme())
+        && key.host().is_some()
+        && key.host() == url.host()
+        && ((!scheme && key.port().is_none())
+            || key.port_or_known_default() == url.port_or_known_default())
+        && url
+            .path()
+            .strip_prefix(path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
 }
 
 impl From<Config> for HashMap<String, String> {
@@ -26,6 +155,36 @@ impl From<Config> for HashMap<String, String> {
         settings_kv.insert("address".to_string(), config.address);
         settings_kv.insert("port".to_string(), config.port.to_string());
         settings_kv.insert("hinting".to_string(), config.hinting.to_string());
+        settings_kv.insert(
+            "timeouts".to_string(),
+            format!(
+                "{}s/{}s/{}s",
+                config.connect_timeout, config.first_token_timeout, config.stall_timeout
+            ),
+        );
+        settings_kv.insert("retries".to_string(), config.retries.to_string());
+        settings_kv.insert(
+            "concurrency".to_string(),
+            match config.concurrency {
+                0 => "unlimited".to_string(),
+                limit => format!("{} per endpoint", limit),
+            },
+        );
+        settings_kv.insert("embeddings".to_string(), config.embedding_model);
+        settings_kv.insert(
+            "record".to_string(),
+            match config.record {
+                true => format!("to {}", config.fixtures),
+                false => "off".to_string(),
+            },
+        );
+        settings_kv.insert(
+            "endpoints".to_string(),
+            match config.endpoints.is_empty() {
+                true => "none".to_string(),
+                false => config.endpoints.into_keys().collect::<Vec<_>>().join(", "),
+            },
+        );
 
         settings_kv
     }
@@ -13,9 +13,11 @@ pub fn init() -> Guards {
 }
 
 pub struct Guards {
+    #[allow(dead_code)]
     worker_guard: WorkerGuard,
 }
 
 pub mod footstones {
+    #[allow(unused_imports)]
     pub use tracing::{info, debug, error, warn};
 }
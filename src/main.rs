@@ -42,12 +42,14 @@ fn main() -> io::Result<()> {
     let mut app = app::App::new(tx);
 
     let (ord_tx, ord_rx) = std::sync::mpsc::channel();
+    let policy = app::RetryPolicy::from(&app.config);
+    let concurrency = app.config.concurrency;
 
     std::thread::spawn(move || {
         let recv = ord_rx;
 
         tow!(async move {
-            let mut pollable_futures = vec![];
+            let mut scheduler = app::Scheduler::new(policy, concurrency);
             // while let Ok(sig) = recv.try_recv() {
             //     info!("Received signal: {:?}", sig);
             //     let fut = tokio::task::spawn(app::handle_streaming_request(sig));
@@ -59,9 +61,13 @@ fn main() -> io::Result<()> {
 
                 for sig in work {
                     info!("Received signal: {:?}", sig);
-                    let fut = tokio::task::spawn(app::handle_streaming_request(sig));
-                    pollable_futures.push(fut);
+                    match sig {
+                        app::Order::Generate(dispatch) => scheduler.submit(dispatch),
+                        app::Order::Stop(chat) => scheduler.stop(chat),
+                        app::Order::Spawn(job) => scheduler.spawn(job),
+                    }
                 }
+                scheduler.poll();
 
                 tokio::time::sleep(Duration::from_millis(1)).await;
             }